@@ -0,0 +1,177 @@
+// An async front end to Mailstrom (the `async` feature).
+//
+// `AsyncMailstrom` hands each call to a thread of its own that owns the
+// `Mailstrom`, and returns a future that completes when that thread has answered.
+// Storage locks and the like are therefore never taken on the async runtime.  The
+// futures are plain `std::future::Future`s that need no particular runtime, so they
+// can be awaited under tokio, async-std or a simple `block_on`.
+
+use crate::config::SendOptions;
+use crate::error::Error;
+use crate::storage::MailstromStorage;
+use crate::worker::WorkerStatus;
+use crate::{Health, Mailstrom, MessageStatus};
+use email_format::Email;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job<S> = Box<dyn FnOnce(&mut Mailstrom<S>) + Send>;
+
+/// A handle to Mailstrom whose methods return futures.
+///
+/// Dropping it drops the `Mailstrom`, which stops the worker.
+pub struct AsyncMailstrom<S: MailstromStorage + 'static> {
+    jobs: mpsc::Sender<Job<S>>,
+}
+
+impl<S: MailstromStorage + 'static> AsyncMailstrom<S> {
+    /// Wrap a `Mailstrom` (already started, if it is to send emails)
+    pub fn new(mailstrom: Mailstrom<S>) -> AsyncMailstrom<S> {
+        let (jobs, receiver) = mpsc::channel::<Job<S>>();
+        let mut mailstrom = mailstrom;
+        let _ = thread::spawn(move || {
+            for job in receiver {
+                job(&mut mailstrom);
+            }
+        });
+        AsyncMailstrom { jobs }
+    }
+
+    /// See `Mailstrom::start`
+    pub async fn start(&self) -> Result<(), Error> {
+        self.call(|mailstrom| mailstrom.start()).await
+    }
+
+    /// See `Mailstrom::die`
+    pub async fn die(&self) -> Result<(), Error> {
+        self.call(|mailstrom| mailstrom.die()).await
+    }
+
+    /// See `Mailstrom::worker_status`
+    pub async fn worker_status(&self) -> Result<WorkerStatus, Error> {
+        self.call(|mailstrom| Ok(mailstrom.worker_status())).await
+    }
+
+    /// See `Mailstrom::health`
+    pub async fn health(&self) -> Result<Health, Error> {
+        self.call(|mailstrom| Ok(mailstrom.health())).await
+    }
+
+    /// See `Mailstrom::send_email`
+    pub async fn send_email(&self, email: Email) -> Result<String, Error> {
+        self.call(move |mailstrom| mailstrom.send_email(email)).await
+    }
+
+    /// See `Mailstrom::send_email_with_options`
+    pub async fn send_email_with_options(&self, email: Email, options: SendOptions)
+                                         -> Result<String, Error>
+    {
+        self.call(move |mailstrom| mailstrom.send_email_with_options(email, options)).await
+    }
+
+    /// See `Mailstrom::send_raw`
+    pub async fn send_raw(&self, from: String, to: Vec<String>, message: Vec<u8>)
+                          -> Result<String, Error>
+    {
+        self.call(move |mailstrom| mailstrom.send_raw(from, to, message)).await
+    }
+
+    /// See `Mailstrom::query_status`
+    pub async fn query_status(&self, message_id: &str) -> Result<MessageStatus, Error> {
+        let message_id = message_id.to_owned();
+        self.call(move |mailstrom| mailstrom.query_status(&message_id)).await
+    }
+
+    /// See `Mailstrom::query_recent`
+    pub async fn query_recent(&self) -> Result<Vec<MessageStatus>, Error> {
+        self.call(|mailstrom| mailstrom.query_recent()).await
+    }
+
+    /// See `Mailstrom::query_page`
+    pub async fn query_page(&self, offset: usize, limit: usize)
+                            -> Result<Vec<MessageStatus>, Error>
+    {
+        self.call(move |mailstrom| mailstrom.query_page(offset, limit)).await
+    }
+
+    /// See `Mailstrom::query_dead_letters`
+    pub async fn query_dead_letters(&self) -> Result<Vec<MessageStatus>, Error> {
+        self.call(|mailstrom| mailstrom.query_dead_letters()).await
+    }
+
+    /// See `Mailstrom::queue_depth`
+    pub async fn queue_depth(&self) -> Result<usize, Error> {
+        self.call(|mailstrom| mailstrom.queue_depth()).await
+    }
+
+    // Run `f` on the thread that owns the Mailstrom
+    fn call<T, F>(&self, f: F) -> Reply<T>
+        where T: Send + 'static,
+              F: FnOnce(&mut Mailstrom<S>) -> Result<T, Error> + Send + 'static
+    {
+        let shared = Arc::new(Mutex::new(Shared { value: None, closed: false, waker: None }));
+        let completer = Completer(Arc::clone(&shared));
+        let job: Job<S> = Box::new(move |mailstrom| completer.complete(f(mailstrom)));
+        // If the thread is gone the job (and its completer) is dropped, closing the reply
+        let _ = self.jobs.send(job);
+        Reply(shared)
+    }
+}
+
+struct Shared<T> {
+    value: Option<Result<T, Error>>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+// The sending half of a reply.  Dropping it without completing (if the job panics)
+// closes the reply.
+struct Completer<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Completer<T> {
+    fn complete(self, value: Result<T, Error>) {
+        if let Ok(mut shared) = self.0.lock() {
+            shared.value = Some(value);
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        let waker = match self.0.lock() {
+            Ok(mut shared) => {
+                shared.closed = true;
+                shared.waker.take()
+            },
+            Err(_) => None,
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+struct Reply<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = match self.0.lock() {
+            Ok(shared) => shared,
+            Err(_) => return Poll::Ready(Err(Error::Lock)),
+        };
+        if let Some(value) = shared.value.take() {
+            return Poll::Ready(value);
+        }
+        if shared.closed {
+            return Poll::Ready(Err(Error::WorkerUnavailable(WorkerStatus::ChannelDisconnected)));
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -1,6 +1,16 @@
 pub use lettre::smtp::authentication::Mechanism;
 pub use trust_dns_resolver::config::{ResolverConfig, ResolverOpts, NameServerConfig, Protocol};
-use std::net::SocketAddr;
+pub use crate::listener::DeliveryListener;
+pub use crate::message_id::MessageIdGenerator;
+pub use crate::retry::RetryPolicy;
+pub use crate::suppression::SuppressionList;
+use crate::delivery_result::DeliveryResult;
+use crate::error::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Authentication settings for an SMTP relay
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -10,13 +20,143 @@ pub struct SmtpAuth {
     pub password: String,
 }
 
+/// Settings for delivering to an LMTP server (such as a local mailstore)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LmtpConfig {
+    pub domain_name: String,
+    /// Defaults to 24
+    pub port: Option<u16>,
+    /// Connect to this Unix domain socket instead of `domain_name` and `port`
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+}
+
 /// Delivery configuration needed if using an SMTP relay
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RelayConfig {
     pub domain_name: String,
     pub port: Option<u16>,
     pub use_tls: bool,
+    /// Start TLS as soon as we connect (SMTPS, as on port 465) rather than with
+    /// STARTTLS.  This is always done on port 465.
+    #[serde(default)]
+    pub implicit_tls: bool,
+    /// Authenticate with the relay.  If it does not offer this mechanism, delivery
+    /// fails rather than going ahead unauthenticated.
     pub auth: Option<SmtpAuth>,
+    /// Connect to this Unix domain socket instead of `domain_name` and `port`.
+    /// STARTTLS is not used over these.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+}
+
+/// A TLS protocol version
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    /// TLS 1.0 (deprecated by RFC 8996, only for legacy servers)
+    Tlsv10,
+    /// TLS 1.1 (deprecated by RFC 8996, only for legacy servers)
+    Tlsv11,
+    #[default]
+    Tlsv12,
+    /// TLS 1.3 (not supported on macOS/iOS)
+    Tlsv13,
+}
+
+impl TlsVersion {
+    // Not to be confused with the DNS `Protocol` exported above
+    pub(crate) fn protocol(self) -> native_tls::Protocol {
+        match self {
+            TlsVersion::Tlsv10 => native_tls::Protocol::Tlsv10,
+            TlsVersion::Tlsv11 => native_tls::Protocol::Tlsv11,
+            TlsVersion::Tlsv12 => native_tls::Protocol::Tlsv12,
+            TlsVersion::Tlsv13 => native_tls::Protocol::Tlsv13,
+        }
+    }
+}
+
+/// When a DSN (delivery status notification) should be sent, per RFC 3461
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DsnNotify {
+    /// Never send a DSN.  This cannot be combined with the others.
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// How much of the message a failure DSN should return, per RFC 3461
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DsnReturn {
+    /// The full message
+    Full,
+    /// Only the headers
+    Headers,
+}
+
+/// Request delivery status notifications from receiving servers.  These parameters
+/// are only sent to servers which advertise DSN support.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DsnRequest {
+    /// When to notify.  If empty, the receiving server's default applies (usually
+    /// failure and delay).
+    pub notify: Vec<DsnNotify>,
+    /// How much of the message to return on failure
+    pub ret: Option<DsnReturn>,
+    /// The envelope id the DSN will echo back.  If None, the message-id is used.
+    pub envid: Option<String>,
+}
+
+impl Default for DsnRequest {
+    fn default() -> DsnRequest {
+        DsnRequest {
+            notify: vec![DsnNotify::Failure],
+            ret: Some(DsnReturn::Headers),
+            envid: None,
+        }
+    }
+}
+
+/// The kind of proxy to tunnel outbound SMTP connections through
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ProxyProtocol {
+    /// A SOCKS5 proxy (RFC 1928)
+    Socks5,
+    /// An HTTP proxy supporting the CONNECT method
+    HttpConnect,
+}
+
+/// Credentials for a proxy
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Proxy configuration for outbound SMTP connections.  The proxy resolves the SMTP
+/// server name, and TLS (including STARTTLS) runs end-to-end through the tunnel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    pub protocol: ProxyProtocol,
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Variable Envelope Return Path settings.  Each recipient is sent the email in a
+/// session of their own, with an envelope sender of `local_part+token@domain`,
+/// where the token encodes the message-id and which recipient it is.
+/// `Mailstrom::decode_verp_address` recovers them from a bounce's address.
+///
+/// Local parts are limited to 64 characters, so long message-ids (or a long
+/// `local_part`) do not fit; such emails are sent with their usual envelope sender.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerpConfig {
+    /// Such as `bounce`
+    pub local_part: String,
+    /// The domain that receives the bounces
+    pub domain: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,7 +169,11 @@ pub enum ResolverSetup {
         socket: SocketAddr,
         protocol: Protocol,
         tls_dns_name: Option<String>
-    }
+    },
+    /// A fully built trust-dns configuration, for setups the others cannot express
+    /// (several name servers, search domains and so on).  `dns_timeout_secs`,
+    /// `dns_attempts` and `validate_dnssec` still override the options if set.
+    Custom(ResolverConfig, ResolverOpts),
 }
 
 impl Default for ResolverSetup {
@@ -41,13 +185,36 @@ impl Default for ResolverSetup {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct RemoteDeliveryConfig {
-    pub resolver_setup: ResolverSetup
+    pub resolver_setup: ResolverSetup,
+    /// How many MX lookups may run at the same time (defaults to 8)
+    pub mx_lookup_concurrency: usize,
+    /// How long (in seconds) to wait for each DNS query.  If None, the system
+    /// configuration's (or the resolver's default of 5 seconds).
+    pub dns_timeout_secs: Option<u64>,
+    /// How many times to try each DNS query before giving up.  If None, the system
+    /// configuration's (or the resolver's default of 2).
+    pub dns_attempts: Option<usize>,
+    /// Validate DNS answers with DNSSEC, treating answers that fail validation as
+    /// lookup failures.  This requires the `dnssec` feature; without it the worker
+    /// will not start (its status is `ResolverCreationFailed`).  Queries always use
+    /// EDNS0, advertising a 1452 byte payload (which the resolver does not let us
+    /// change).
+    pub validate_dnssec: bool,
+    /// Servers to deliver to for these domains instead of looking up their MX
+    /// records, such as `internal.corp` to `["mail.internal.corp"]`.  They are
+    /// tried in the order given.
+    pub mx_overrides: HashMap<String, Vec<String>>,
 }
 
 impl Default for RemoteDeliveryConfig {
     fn default() -> RemoteDeliveryConfig {
         RemoteDeliveryConfig {
-            resolver_setup: Default::default()
+            resolver_setup: Default::default(),
+            mx_lookup_concurrency: 8,
+            dns_timeout_secs: None,
+            dns_attempts: None,
+            validate_dnssec: false,
+            mx_overrides: HashMap::new(),
         }
     }
 }
@@ -58,7 +225,23 @@ pub enum DeliveryConfig {
     /// Deliver everything through an SMTP relay
     Relay(RelayConfig),
     /// Deliver directly directly to recipient domain MX servers
-    Remote(RemoteDeliveryConfig)
+    Remote(RemoteDeliveryConfig),
+    /// Hand everything to a local sendmail binary, run as
+    /// `path <args> -f <sender> -- <recipients>` with the message on stdin.
+    /// `args` usually includes `-i`. The exit code determines the result.
+    Sendmail {
+        path: PathBuf,
+        args: Vec<String>,
+    },
+    /// Deliver everything to an LMTP server, which reports a result for each
+    /// recipient separately
+    Lmtp(LmtpConfig),
+    /// Deliver nothing, without touching the network: each delivery attempt just
+    /// gives every recipient the simulated outcome.  For testing code that handles
+    /// delivery statuses.
+    Discard {
+        simulate: SimulatedOutcome,
+    },
 }
 
 impl Default for DeliveryConfig {
@@ -67,6 +250,83 @@ impl Default for DeliveryConfig {
     }
 }
 
+/// What happens to recipients under `DeliveryConfig::Discard`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SimulatedOutcome {
+    /// Every recipient is delivered
+    Delivered,
+    /// Every recipient is deferred, and so retried until they run out of attempts
+    Deferred,
+    /// Every recipient fails
+    Failed,
+    /// Each recipient gets the result listed for their address (a `Deferred` count
+    /// is replaced by the attempt number); anyone not listed is delivered
+    Scripted(HashMap<String, DeliveryResult>),
+}
+
+/// Per-email overrides of `Config` settings, for `Mailstrom::send_email_with_options`.
+/// These are stored with the email, so that retries (even after a restart) use them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SendOptions {
+    /// Overrides `Config::helo_name` (which is also the default message-id domain)
+    pub helo_name: Option<String>,
+    /// Overrides `Config::smtp_timeout_secs`
+    pub smtp_timeout_secs: Option<u64>,
+    /// Overrides `Config::require_tls`
+    pub require_tls: Option<bool>,
+    /// Overrides `Config::delivery`
+    pub delivery: Option<DeliveryConfig>,
+    /// Overrides `Config::undisclosed_recipients`
+    pub undisclosed_recipients: Option<bool>,
+    /// Identifies the email to Mailstrom, so that sending it again (say after
+    /// a crash) is harmless: if an email with the same key was sent within
+    /// `Config::idempotency_window_secs`, its message-id is returned instead of
+    /// sending another copy.  This needs a storage that implements
+    /// `MailstromStorage::lookup_idempotency_key` (`MemoryStorage` does).
+    pub idempotency_key: Option<String>,
+    /// Overrides `Config::envelope_from_override`
+    pub envelope_from_override: Option<String>,
+    /// Emails of higher priority (such as transactional ones) are delivered before
+    /// those of lower priority (such as bulk mail) that are due at the same time.
+    /// The default is 0, the lowest.
+    pub priority: u8,
+}
+
+impl SendOptions {
+    /// Whether nothing is overridden (and there is no idempotency key or priority)
+    pub fn is_empty(&self) -> bool {
+        self.helo_name.is_none() && self.smtp_timeout_secs.is_none()
+            && self.require_tls.is_none() && self.delivery.is_none()
+            && self.undisclosed_recipients.is_none() && self.idempotency_key.is_none()
+            && self.envelope_from_override.is_none() && self.priority == 0
+    }
+
+    /// The configuration with these overrides applied
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(ref helo_name) = self.helo_name {
+            config.helo_name = helo_name.clone();
+        }
+        if let Some(smtp_timeout_secs) = self.smtp_timeout_secs {
+            config.smtp_timeout_secs = smtp_timeout_secs;
+        }
+        if let Some(require_tls) = self.require_tls {
+            config.require_tls = require_tls;
+        }
+        if let Some(ref delivery) = self.delivery {
+            config.delivery = delivery.clone();
+        }
+        if let Some(undisclosed_recipients) = self.undisclosed_recipients {
+            config.undisclosed_recipients = undisclosed_recipients;
+        }
+        if let Some(ref address) = self.envelope_from_override {
+            config.envelope_from_override = Some(address.clone());
+        }
+        config
+    }
+}
+
 /// Mailstrom configuration settings
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -74,8 +334,151 @@ pub struct Config {
     pub helo_name: String,
     pub smtp_timeout_secs: u64,
     pub base_resend_delay_secs: u64,
+    /// How many worker passes may defer a recipient before they are failed.  A pass
+    /// is one attempt however many MX servers it tries.  Each recipient has their
+    /// own count, so one that is requeued gets a full set of attempts whatever the
+    /// others have used.
+    pub max_attempts_per_recipient: u8,
+    /// Decides when to retry deferred recipients, or to give up on them early.  If
+    /// None, the delay is `base_resend_delay_secs` times 3 to the power of the
+    /// attempt.  This is not serialized.
+    #[serde(skip)]
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
+    /// The most SMTP connections to have open at once.  Deliveries beyond this
+    /// wait for a connection to close (rather than being deferred).  0 means no
+    /// limit.
+    pub max_concurrent_connections: usize,
+    /// The most SMTP connections to have open at once to deliver to the recipients
+    /// of any one domain (such as `gmail.com`), with remote delivery.  Deliveries
+    /// beyond this wait rather than being deferred.  0 means no limit.
+    pub max_connections_per_domain: usize,
+    /// The most MX servers to try a recipient at in one pass, with remote delivery.
+    /// The next pass carries on from the server after the last one tried, after the
+    /// retry delay.  0 means every server is tried each pass.
+    pub max_mx_per_pass: usize,
+    /// The most emails that may be waiting to be delivered.  Sending more returns
+    /// `Error::QueueFull` (and does not store them) until some are done.  If None,
+    /// there is no limit.
+    pub max_queue_depth: Option<usize>,
+    /// How many requests (such as emails to deliver) may wait for the worker to
+    /// pick them up.  The worker only picks them up between deliveries, so a caller
+    /// sending faster than it delivers fills this up.  At least 1.
+    pub worker_channel_capacity: usize,
+    /// How long (in milliseconds) to wait for room when `worker_channel_capacity`
+    /// requests are already waiting, before giving up with `Error::WorkerBusy`
+    /// (`Error::Send` for `Mailstrom::start` and `Mailstrom::die`).
+    pub worker_send_timeout_ms: u64,
+    /// How long (in seconds) an email sent with `SendOptions::idempotency_key` is
+    /// remembered: sending another with the same key within this time returns the
+    /// first one's message-id.
+    pub idempotency_window_secs: u64,
+    /// The largest message (in bytes, with its headers) to accept.  Larger ones are
+    /// refused with `Error::MessageTooLarge` rather than stored.  If None, there is
+    /// no limit.
+    pub max_message_bytes: Option<usize>,
+    /// Refuse to send over a connection that STARTTLS could not encrypt (with a
+    /// relay, only if its `use_tls` is set).  `relay_require_tls` and
+    /// `remote_require_tls` override this for each kind of delivery.
     pub require_tls: bool,
+    /// Whether to require TLS with an SMTP relay, if not `require_tls`
+    pub relay_require_tls: Option<bool>,
+    /// Whether to require TLS with MX servers (for remote delivery), if not
+    /// `require_tls`.  Many MX servers on the internet do not support it.
+    pub remote_require_tls: Option<bool>,
+    /// When TLS is required but a server does not offer STARTTLS, defer its
+    /// recipients (to be retried, or tried at the next MX server) rather than fail
+    /// them.  A failed TLS handshake is always deferred when TLS is required.
+    pub defer_when_tls_unavailable: bool,
+    /// Greet servers that refuse EHLO (or garble the reply to it) with HELO instead,
+    /// without any SMTP extensions.  This is on by default, for the long tail of old
+    /// or misconfigured servers.
+    pub allow_helo_fallback: bool,
+    /// Send messages with BDAT (RFC 3030 CHUNKING) instead of DATA, to servers that
+    /// support it
+    pub prefer_chunking: bool,
+    /// The most bytes of the message to send in each BDAT chunk
+    pub chunk_size: usize,
+    /// What to make of a server that takes the whole message but then times out
+    /// before replying.  It may well have accepted the message, so retrying risks
+    /// delivering it twice.  If true the recipients are taken to be delivered (with
+    /// a `DeliveredResponse` code of 0); if false, the default, they are deferred
+    /// like any other timeout.  Timeouts earlier in the session are always deferred.
+    pub assume_delivered_on_post_data_timeout: bool,
+    /// Go through each SMTP session (including the MX lookup and STARTTLS) up to
+    /// RCPT TO, then send RSET instead of the message.  Accepted recipients are
+    /// Delivered with `DeliveredResponse::dry_run` set, and the reply to RCPT TO.
+    /// This checks that the servers can be reached (and will accept the recipients)
+    /// without sending anything.  It does not apply to sendmail or LMTP delivery.
+    pub dry_run: bool,
+    /// The lowest TLS version we will negotiate
+    pub min_tls_version: TlsVersion,
+    /// Accept TLS certificates that do not verify (expired, self-signed, etc).
+    /// This is dangerous and only meant for testing environments.
+    pub danger_accept_invalid_certs: bool,
+    /// Accept TLS certificates whose name does not match the server.
+    /// This is dangerous and only meant for testing environments.
+    pub danger_accept_invalid_hostnames: bool,
     pub delivery: DeliveryConfig,
+    /// Request delivery status notifications (RFC 3461)
+    pub dsn: Option<DsnRequest>,
+    /// Tunnel outbound SMTP connections through this proxy
+    pub proxy: Option<ProxyConfig>,
+    /// Local address to bind outgoing SMTP connections to.  If None, the operating
+    /// system chooses.
+    pub source_addr: Option<IpAddr>,
+    /// Local addresses to rotate through, round-robin per message.  If this is not
+    /// empty, it overrides `source_addr`.
+    pub source_addr_rotation: Vec<IpAddr>,
+    /// HELO names for connections from particular local addresses (so that each can
+    /// match its address's PTR record), used instead of `helo_name` (even one from
+    /// `SendOptions`)
+    pub helo_map: HashMap<IpAddr, String>,
+    /// Recipients on this list are failed without being sent to, and recipients whose
+    /// mailbox is reported unknown are added to it. This is not serialized.
+    #[serde(skip)]
+    pub suppression_list: Option<SuppressionList>,
+    /// Notified of each delivery attempt and result.  This is not serialized.
+    #[serde(skip)]
+    pub listener: Option<Arc<dyn DeliveryListener>>,
+    /// Check that the sender domain (from the Sender, or else the From header) is a
+    /// syntactically valid domain before accepting an email
+    pub check_sender_domain: bool,
+    /// Also check that the sender domain has MX or A/AAAA records in DNS.  This
+    /// requires `check_sender_domain`, and costs a lookup per email.
+    pub resolve_sender_domain: bool,
+    /// The domain for generated message-ids.  If None, `helo_name` is used.
+    pub message_id_domain: Option<String>,
+    /// Generates message-ids instead (overriding `message_id_domain`).  This is not
+    /// serialized.
+    #[serde(skip)]
+    pub message_id_generator: Option<Arc<dyn MessageIdGenerator>>,
+    /// Give emails with only Bcc recipients (no To or Cc header) a
+    /// `To: undisclosed-recipients:;` header, since some servers dislike messages
+    /// without one
+    pub undisclosed_recipients: bool,
+    /// The envelope sender (and `Return-Path`) for emails, so that bounces go to a
+    /// dedicated mailbox.  If None, the From address is used.  This does not apply
+    /// to `Mailstrom::send_raw`, which is given its envelope sender.
+    pub bounce_address: Option<String>,
+    /// The envelope sender (SMTP MAIL FROM) for emails, such as an address at your
+    /// own bounce domain when sending on behalf of others.  Unlike `bounce_address`,
+    /// this leaves the message alone: its headers, `Return-Path` included, are
+    /// as they would be without it.  This does not apply to `Mailstrom::send_raw`,
+    /// and `verp` still takes precedence.
+    pub envelope_from_override: Option<String>,
+    /// Give each recipient their own envelope sender, to tell which email and
+    /// recipient a bounce is for (this overrides `bounce_address` for the envelope)
+    pub verp: Option<VerpConfig>,
+    /// Send every email to this address instead of its recipients, for staging
+    /// environments that must never mail real people.  The intended recipients
+    /// (Bcc recipients included) are listed in `X-Original-To` headers.
+    pub redirect_all_to: Option<String>,
+    /// If set, recipients are only sent to at these domains; any others fail
+    /// straight away.  Domains match exactly (ignoring case), not their subdomains.
+    pub allowed_domains: Option<Vec<String>>,
+    /// Recipients at these domains fail straight away, even if they are in
+    /// `allowed_domains`
+    pub denied_domains: Vec<String>,
 }
 
 impl Default for Config {
@@ -84,8 +487,246 @@ impl Default for Config {
             helo_name: "localhost".to_string(),
             smtp_timeout_secs: 60,
             base_resend_delay_secs: 60,
+            max_attempts_per_recipient: 3,
+            retry_policy: None,
+            max_concurrent_connections: 0,
+            max_connections_per_domain: 0,
+            max_mx_per_pass: 0,
+            max_queue_depth: None,
+            worker_channel_capacity: 1024,
+            worker_send_timeout_ms: 1000,
+            idempotency_window_secs: 24 * 60 * 60,
+            max_message_bytes: None,
             require_tls: false,
+            relay_require_tls: None,
+            remote_require_tls: None,
+            defer_when_tls_unavailable: false,
+            allow_helo_fallback: true,
+            prefer_chunking: false,
+            chunk_size: 1024 * 1024,
+            assume_delivered_on_post_data_timeout: false,
+            dry_run: false,
+            min_tls_version: Default::default(),
+            danger_accept_invalid_certs: false,
+            danger_accept_invalid_hostnames: false,
             delivery: Default::default(),
+            dsn: None,
+            proxy: None,
+            source_addr: None,
+            source_addr_rotation: vec![],
+            helo_map: HashMap::new(),
+            suppression_list: None,
+            listener: None,
+            check_sender_domain: true,
+            resolve_sender_domain: false,
+            message_id_domain: None,
+            message_id_generator: None,
+            undisclosed_recipients: false,
+            bounce_address: None,
+            envelope_from_override: None,
+            verp: None,
+            redirect_all_to: None,
+            allowed_domains: None,
+            denied_domains: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load a configuration from a TOML file.  Settings that are left out take
+    /// their default values.  Settings that are not serialized (like the
+    /// `listener`) must be set afterwards.
+    ///
+    /// Enums with data are written as inline tables naming the variant, such as
+    /// `delivery = { Relay = { domain_name = "smtp.example.com", use_tls = true } }`
+    /// (the TOML parser does not accept `[delivery.Relay]` table headers for them).
+    pub fn from_toml_path<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        ::toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Load a configuration from a JSON file, like `from_toml_path`
+    pub fn from_json_path<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        ::serde_json::from_str(&contents)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Build a configuration from environment variables, using the default for each
+    /// setting whose variable is not set:
+    ///
+    /// * `MAILSTROM_HELO_NAME`, `MAILSTROM_MESSAGE_ID_DOMAIN`, `MAILSTROM_REDIRECT_ALL_TO`
+    /// * `MAILSTROM_SMTP_TIMEOUT_SECS`, `MAILSTROM_BASE_RESEND_DELAY_SECS`,
+    ///   `MAILSTROM_MAX_ATTEMPTS_PER_RECIPIENT` (numbers)
+    /// * `MAILSTROM_REQUIRE_TLS`, `MAILSTROM_RELAY_REQUIRE_TLS`,
+    ///   `MAILSTROM_REMOTE_REQUIRE_TLS` (`true`/`false`, `yes`/`no` or `1`/`0`)
+    /// * `MAILSTROM_DELIVERY`: `remote`, `relay` or `discard` (every recipient is
+    ///   delivered, see `SimulatedOutcome::Delivered`)
+    /// * For relay delivery: `MAILSTROM_RELAY_HOST` (required), `MAILSTROM_RELAY_PORT`,
+    ///   `MAILSTROM_RELAY_USE_TLS` (defaults to true), `MAILSTROM_RELAY_IMPLICIT_TLS`,
+    ///   and for authentication
+    ///   `MAILSTROM_RELAY_USER` and `MAILSTROM_RELAY_PASSWORD` with
+    ///   `MAILSTROM_RELAY_AUTH_MECHANISM` (`plain`, the default, `login` or `xoauth2`)
+    ///
+    /// If any are malformed, the error lists all of them.
+    pub fn from_env() -> Result<Config, Error> {
+        Config::from_vars(|name| ::std::env::var(name).ok())
+    }
+
+    // from_env(), reading variables through `var`
+    pub(crate) fn from_vars<F>(var: F) -> Result<Config, Error>
+        where F: Fn(&str) -> Option<String>
+    {
+        let mut env = EnvParser { var, problems: Vec::new() };
+        let mut config = Config::default();
+
+        if let Some(helo_name) = env.get("MAILSTROM_HELO_NAME") {
+            config.helo_name = helo_name;
+        }
+        if let Some(domain) = env.get("MAILSTROM_MESSAGE_ID_DOMAIN") {
+            config.message_id_domain = Some(domain);
+        }
+        if let Some(address) = env.get("MAILSTROM_REDIRECT_ALL_TO") {
+            config.redirect_all_to = Some(address);
+        }
+        if let Some(secs) = env.parse("MAILSTROM_SMTP_TIMEOUT_SECS") {
+            config.smtp_timeout_secs = secs;
+        }
+        if let Some(secs) = env.parse("MAILSTROM_BASE_RESEND_DELAY_SECS") {
+            config.base_resend_delay_secs = secs;
+        }
+        if let Some(attempts) = env.parse("MAILSTROM_MAX_ATTEMPTS_PER_RECIPIENT") {
+            config.max_attempts_per_recipient = attempts;
+        }
+        if let Some(require_tls) = env.bool("MAILSTROM_REQUIRE_TLS") {
+            config.require_tls = require_tls;
+        }
+        if let Some(require_tls) = env.bool("MAILSTROM_RELAY_REQUIRE_TLS") {
+            config.relay_require_tls = Some(require_tls);
         }
+        if let Some(require_tls) = env.bool("MAILSTROM_REMOTE_REQUIRE_TLS") {
+            config.remote_require_tls = Some(require_tls);
+        }
+
+        match env.get("MAILSTROM_DELIVERY").map(|d| d.to_lowercase()) {
+            None => {}
+            Some(ref d) if d == "remote" => {
+                config.delivery = DeliveryConfig::Remote(Default::default());
+            }
+            Some(ref d) if d == "relay" => {
+                if let Some(relay_config) = env.relay_config() {
+                    config.delivery = DeliveryConfig::Relay(relay_config);
+                }
+            }
+            Some(ref d) if d == "discard" => {
+                config.delivery = DeliveryConfig::Discard {
+                    simulate: SimulatedOutcome::Delivered,
+                };
+            }
+            Some(d) => env.problem("MAILSTROM_DELIVERY",
+                                   format!("expected remote, relay or discard, not {:?}", d)),
+        }
+
+        if env.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(Error::Config(format!("Malformed environment variables: {}",
+                                      env.problems.join("; "))))
+        }
+    }
+}
+
+// Reads environment variables, collecting a description of each malformed one
+struct EnvParser<F> {
+    var: F,
+    problems: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvParser<F> {
+    // The value of a variable, if it is set (and not empty)
+    fn get(&self, name: &str) -> Option<String> {
+        (self.var)(name).and_then(|v| {
+            let v = v.trim();
+            if v.is_empty() { None } else { Some(v.to_owned()) }
+        })
+    }
+
+    fn problem(&mut self, name: &str, problem: String) {
+        self.problems.push(format!("{}: {}", name, problem));
+    }
+
+    fn parse<T: ::std::str::FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = self.get(name)?;
+        match value.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.problem(name, format!("{:?} is not a valid number", value));
+                None
+            }
+        }
+    }
+
+    fn bool(&mut self, name: &str) -> Option<bool> {
+        let value = self.get(name)?;
+        match &*value.to_lowercase() {
+            "true" | "yes" | "1" => Some(true),
+            "false" | "no" | "0" => Some(false),
+            _ => {
+                self.problem(name, format!("{:?} is not true or false", value));
+                None
+            }
+        }
+    }
+
+    fn relay_config(&mut self) -> Option<RelayConfig> {
+        let domain_name = self.get("MAILSTROM_RELAY_HOST");
+        if domain_name.is_none() {
+            self.problem("MAILSTROM_RELAY_HOST", "required for relay delivery".to_owned());
+        }
+        let port = self.parse("MAILSTROM_RELAY_PORT");
+        let use_tls = self.bool("MAILSTROM_RELAY_USE_TLS").unwrap_or(true);
+        let implicit_tls = self.bool("MAILSTROM_RELAY_IMPLICIT_TLS").unwrap_or(false);
+
+        let mechanism = match self.get("MAILSTROM_RELAY_AUTH_MECHANISM")
+            .map(|m| m.to_lowercase())
+        {
+            None => Mechanism::Plain,
+            Some(ref m) if m == "plain" => Mechanism::Plain,
+            Some(ref m) if m == "login" => Mechanism::Login,
+            Some(ref m) if m == "xoauth2" => Mechanism::Xoauth2,
+            Some(m) => {
+                self.problem("MAILSTROM_RELAY_AUTH_MECHANISM",
+                             format!("expected plain, login or xoauth2, not {:?}", m));
+                Mechanism::Plain
+            }
+        };
+        let username = self.get("MAILSTROM_RELAY_USER");
+        let password = self.get("MAILSTROM_RELAY_PASSWORD");
+        let auth = match (username, password) {
+            (Some(username), Some(password)) => Some(SmtpAuth { mechanism, username, password }),
+            (None, None) => None,
+            (Some(_), None) => {
+                let problem = "required with MAILSTROM_RELAY_USER".to_owned();
+                self.problem("MAILSTROM_RELAY_PASSWORD", problem);
+                None
+            }
+            (None, Some(_)) => {
+                let problem = "required with MAILSTROM_RELAY_PASSWORD".to_owned();
+                self.problem("MAILSTROM_RELAY_USER", problem);
+                None
+            }
+        };
+
+        Some(RelayConfig {
+            domain_name: domain_name?,
+            port,
+            use_tls,
+            implicit_tls,
+            auth,
+            unix_socket: None,
+        })
     }
 }
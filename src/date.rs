@@ -0,0 +1,57 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+/// Format a time as an RFC 5322 date (such as `"Mon, 05 Jan 2015 16:13:05 +1300"`),
+/// suitable for `Email::new` or `Email::set_date`.
+///
+/// `utc_offset_secs` is the offset of the timezone to show the time in, east of UTC
+/// (so +1300 is `13 * 3600`).  Only whole minutes of the offset are shown.
+pub fn rfc5322_date(time: SystemTime, utc_offset_secs: i32) -> String {
+    let unix_secs: i64 = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let local_secs = unix_secs + i64::from(utc_offset_secs);
+
+    let days = local_secs.div_euclid(86400);
+    let secs_of_day = local_secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 4).rem_euclid(7) as usize;
+
+    let sign = if utc_offset_secs < 0 { '-' } else { '+' };
+    let offset_mins = utc_offset_secs.unsigned_abs() / 60;
+
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+        DAYS[weekday],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60,
+        sign,
+        offset_mins / 60,
+        offset_mins % 60
+    )
+}
+
+// Convert days since 1970-01-01 into a (year, month, day) in the proleptic Gregorian
+// calendar (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097); // day of era
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365; // year of era
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // day of year (from March)
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -1,5 +1,24 @@
+use crate::enhanced_status::EnhancedStatusCode;
+use std::fmt;
+
 /// The result (so far) of the sending of an email to a particular recipient
+///
+/// This serializes as an adjacently tagged enum, which external consumers may rely on:
+///
+/// ```json
+/// {"status": "Queued"}
+/// {"status": "Deferred", "details": [2, "451 4.7.1 Greylisted"]}
+/// {"status": "Delivered",
+///  "details": {"code": 250, "message": "2.0.0 Ok", "mx_host": "mx.example.com",
+///              "encrypted": true, "used_helo": false, "dry_run": false}}
+/// {"status": "Failed", "details": "550 5.1.1 User unknown"}
+/// ```
+///
+/// Results stored by older versions, which were externally tagged (such as
+/// `{"Deferred": [2, "451 4.7.1 Greylisted"]}` or `{"Delivered": "250 Ok"}`), still
+/// deserialize.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "details", from = "StoredDeliveryResult")]
 pub enum DeliveryResult {
     /// Mail is queued to be sent, but no attempt has yet been made to send. This state should
     /// be moved through rather quickly.
@@ -10,7 +29,7 @@ pub enum DeliveryResult {
     Deferred(u8, String),
 
     /// Mail has been sent. Delivery response included.
-    Delivered(String),
+    Delivered(DeliveredResponse),
 
     /// Mail sending has failed due to a permanent error. Error is included.
     Failed(String),
@@ -23,4 +42,142 @@ impl DeliveryResult {
             _ => true,
         }
     }
+
+    /// The RFC 3463 enhanced status code from the server response, if there was one
+    pub fn enhanced_status_code(&self) -> Option<EnhancedStatusCode> {
+        match *self {
+            DeliveryResult::Queued => None,
+            DeliveryResult::Deferred(_, ref msg)
+                | DeliveryResult::Failed(ref msg) => EnhancedStatusCode::find_in(msg),
+            DeliveryResult::Delivered(ref response) =>
+                EnhancedStatusCode::find_in(&response.message),
+        }
+    }
+}
+
+impl fmt::Display for DeliveryResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeliveryResult::Queued => write!(f, "Queued"),
+            DeliveryResult::Deferred(attempts, ref msg) =>
+                write!(f, "Deferred (attempt {}): {}", attempts, msg),
+            DeliveryResult::Delivered(ref response) if response.dry_run =>
+                write!(f, "Delivered (dry run): {} {}", response.code, response.message),
+            // There is no reply code when delivering with sendmail
+            DeliveryResult::Delivered(ref response) if response.code == 0 =>
+                write!(f, "Delivered: {}", response.message),
+            DeliveryResult::Delivered(ref response) =>
+                write!(f, "Delivered: {} {}", response.code, response.message),
+            DeliveryResult::Failed(ref msg) => write!(f, "Failed: {}", msg),
+        }
+    }
+}
+
+// What a DeliveryResult may have been stored as
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDeliveryResult {
+    Current(CurrentDeliveryResult),
+    // Older versions used serde's default, external tagging
+    External(ExternalDeliveryResult),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "status", content = "details")]
+enum CurrentDeliveryResult {
+    Queued,
+    Deferred(u8, String),
+    Delivered(DeliveredResponse),
+    Failed(String),
+}
+
+#[derive(Deserialize)]
+enum ExternalDeliveryResult {
+    Queued,
+    Deferred(u8, String),
+    Delivered(DeliveredResponse),
+    Failed(String),
+}
+
+impl From<StoredDeliveryResult> for DeliveryResult {
+    fn from(stored: StoredDeliveryResult) -> DeliveryResult {
+        match stored {
+            StoredDeliveryResult::Current(result) => match result {
+                CurrentDeliveryResult::Queued => DeliveryResult::Queued,
+                CurrentDeliveryResult::Deferred(attempts, msg) =>
+                    DeliveryResult::Deferred(attempts, msg),
+                CurrentDeliveryResult::Delivered(response) => DeliveryResult::Delivered(response),
+                CurrentDeliveryResult::Failed(msg) => DeliveryResult::Failed(msg),
+            },
+            StoredDeliveryResult::External(result) => match result {
+                ExternalDeliveryResult::Queued => DeliveryResult::Queued,
+                ExternalDeliveryResult::Deferred(attempts, msg) =>
+                    DeliveryResult::Deferred(attempts, msg),
+                ExternalDeliveryResult::Delivered(response) => DeliveryResult::Delivered(response),
+                ExternalDeliveryResult::Failed(msg) => DeliveryResult::Failed(msg),
+            },
+        }
+    }
+}
+
+/// How a server accepted a message for a recipient, as proof of acceptance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StoredDeliveredResponse")]
+pub struct DeliveredResponse {
+    /// The SMTP (or LMTP) reply code, such as 250.  This is 0 if there was no reply
+    /// (when delivering with sendmail).
+    pub code: u16,
+    /// The text of the reply (or what sendmail said)
+    pub message: String,
+    /// The server that accepted it: the MX host, or the relay, LMTP server or
+    /// sendmail binary
+    pub mx_host: String,
+    /// Whether the SMTP session was encrypted with TLS (by STARTTLS or implicit
+    /// TLS), and so with at least `Config::min_tls_version`.  Which protocol version
+    /// and cipher suite were negotiated is not recorded: native-tls does not tell
+    /// us, and lettre does not give us the stream it upgrades with STARTTLS.
+    pub encrypted: bool,
+    /// Whether the server only accepted HELO, not EHLO (see
+    /// `Config::allow_helo_fallback`), so no SMTP extensions could be used
+    pub used_helo: bool,
+    /// Whether this was a dry run (see `Config::dry_run`): the server accepted the
+    /// recipient, and the reply is to RCPT TO, but the message was not sent
+    pub dry_run: bool,
+}
+
+// Older versions stored just a description of the response (as
+// `{"Delivered": "250 Ok"}`; see ExternalDeliveryResult)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDeliveredResponse {
+    Current {
+        code: u16,
+        message: String,
+        mx_host: String,
+        #[serde(default)]
+        encrypted: bool,
+        #[serde(default)]
+        used_helo: bool,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    Description(String),
+}
+
+impl From<StoredDeliveredResponse> for DeliveredResponse {
+    fn from(stored: StoredDeliveredResponse) -> DeliveredResponse {
+        match stored {
+            StoredDeliveredResponse::Current {
+                code, message, mx_host, encrypted, used_helo, dry_run
+            } => DeliveredResponse { code, message, mx_host, encrypted, used_helo, dry_run },
+            StoredDeliveredResponse::Description(message) => DeliveredResponse {
+                code: message.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0),
+                message,
+                mx_host: String::new(),
+                encrypted: false,
+                used_helo: false,
+                dry_run: false,
+            },
+        }
+    }
 }
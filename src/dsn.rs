@@ -0,0 +1,194 @@
+//! Parsing of inbound delivery status notifications (bounces), per RFC 3464.
+//!
+//! When you request DSNs (see `config::DsnRequest`), receiving servers report back
+//! with a `multipart/report; report-type=delivery-status` message.  `parse_dsn` reads
+//! such a message and extracts what happened to each recipient, and
+//! `bounced_message_ids` finds which of our emails a bounce is about.
+
+use crate::enhanced_status::EnhancedStatusCode;
+use crate::error::Error;
+
+/// The action reported for a recipient
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DsnAction {
+    Failed,
+    Delayed,
+    Delivered,
+    Relayed,
+    Expanded,
+    Other(String),
+}
+
+/// What a DSN reports about one recipient
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecipientDsn {
+    /// The envelope id (ENVID) of the original message, from the per-message fields
+    pub original_envelope_id: Option<String>,
+    /// The recipient address, as the reporting server saw it (Final-Recipient)
+    pub final_recipient: String,
+    /// The recipient address, as it was originally given (Original-Recipient)
+    pub original_recipient: Option<String>,
+    pub action: DsnAction,
+    pub status: Option<EnhancedStatusCode>,
+    /// The server response that caused this notification (Diagnostic-Code)
+    pub diagnostic_code: Option<String>,
+    /// The server that reported it (Remote-MTA)
+    pub remote_mta: Option<String>,
+}
+
+/// Parse a raw delivery status notification message into per-recipient reports
+pub fn parse_dsn(raw: &[u8]) -> Result<Vec<RecipientDsn>, Error> {
+    let text = String::from_utf8_lossy(raw).replace("\r\n", "\n");
+
+    let (headers, body) = split_header_body(&text);
+    let headers = parse_fields(headers);
+
+    let content_type = field(&headers, "content-type")
+        .ok_or_else(|| invalid("missing Content-Type"))?;
+    if !content_type.to_lowercase().starts_with("multipart/report") {
+        return Err(invalid("not a multipart/report message"));
+    }
+    let boundary = parameter(content_type, "boundary")
+        .ok_or_else(|| invalid("missing multipart boundary"))?;
+
+    // Find the delivery-status part
+    let delimiter = format!("--{}", boundary);
+    let status_part = body
+        .split(&*delimiter)
+        .skip(1) // the preamble
+        .map(|part| split_header_body(part.trim_start_matches('\n')))
+        .find(|&(part_headers, _)| {
+            field(&parse_fields(part_headers), "content-type")
+                .map(|ct| {
+                    let ct = ct.to_lowercase();
+                    ct.starts_with("message/delivery-status")
+                        || ct.starts_with("message/global-delivery-status")
+                })
+                .unwrap_or(false)
+        })
+        .map(|(_, part_body)| part_body)
+        .ok_or_else(|| invalid("no message/delivery-status part"))?;
+
+    // The first group of fields is per-message, the rest are per-recipient
+    let mut groups = status_part
+        .split("\n\n")
+        .map(parse_fields)
+        .filter(|fields| !fields.is_empty());
+
+    let per_message = groups.next().ok_or_else(|| invalid("empty delivery-status part"))?;
+    let original_envelope_id = field(&per_message, "original-envelope-id").map(|s| s.to_owned());
+
+    let mut reports = Vec::new();
+    for fields in groups {
+        let final_recipient = field(&fields, "final-recipient")
+            .map(strip_type)
+            .ok_or_else(|| invalid("recipient fields without Final-Recipient"))?;
+        let action = match field(&fields, "action").map(|a| a.to_lowercase()) {
+            Some(ref a) if a == "failed" => DsnAction::Failed,
+            Some(ref a) if a == "delayed" => DsnAction::Delayed,
+            Some(ref a) if a == "delivered" => DsnAction::Delivered,
+            Some(ref a) if a == "relayed" => DsnAction::Relayed,
+            Some(ref a) if a == "expanded" => DsnAction::Expanded,
+            Some(a) => DsnAction::Other(a),
+            None => return Err(invalid("recipient fields without Action")),
+        };
+        reports.push(RecipientDsn {
+            original_envelope_id: original_envelope_id.clone(),
+            final_recipient: final_recipient.to_owned(),
+            original_recipient: field(&fields, "original-recipient")
+                .map(|s| strip_type(s).to_owned()),
+            action,
+            status: field(&fields, "status").and_then(EnhancedStatusCode::find_in),
+            diagnostic_code: field(&fields, "diagnostic-code").map(|s| strip_type(s).to_owned()),
+            remote_mta: field(&fields, "remote-mta").map(|s| strip_type(s).to_owned()),
+        });
+    }
+
+    if reports.is_empty() {
+        return Err(invalid("no per-recipient fields"));
+    }
+
+    Ok(reports)
+}
+
+/// Find the message-ids that a bounce may be about, most likely first: the
+/// Original-Envelope-Id of a DSN (which is the message-id, unless
+/// `DsnRequest::envid` was set), then any Message-ID among the returned headers of
+/// the original message.  This works for bounces that are not DSNs as well, if they
+/// quote the original headers.  The bounce's own Message-ID is not included.
+pub fn bounced_message_ids(raw: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(raw).replace("\r\n", "\n");
+
+    // Everything after the bounce's own header section
+    let (_, body) = split_header_body(&text);
+    let fields = parse_fields(body);
+
+    let mut ids: Vec<String> = Vec::new();
+    for name in &["original-envelope-id", "message-id"] {
+        for (_, value) in fields.iter().filter(|f| f.0 == *name) {
+            let id = value.trim().trim_start_matches('<').trim_end_matches('>').trim();
+            if !id.is_empty() && !ids.iter().any(|i| i == id) {
+                ids.push(id.to_owned());
+            }
+        }
+    }
+    ids
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidDsn(reason.to_owned())
+}
+
+// Split at the first blank line
+fn split_header_body(text: &str) -> (&str, &str) {
+    match text.find("\n\n") {
+        Some(pos) => (&text[..pos], &text[pos + 2..]),
+        None => (text, ""),
+    }
+}
+
+// Parse header-style fields into (lowercased name, unfolded value) pairs
+fn parse_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Continuation of the previous field
+            if let Some(last) = fields.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            fields.push((
+                line[..colon].trim().to_lowercase(),
+                line[colon + 1..].trim().to_owned(),
+            ));
+        }
+    }
+    fields
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields.iter().find(|f| f.0 == name).map(|f| &*f.1)
+}
+
+// Get a parameter (such as boundary) from a Content-Type value
+fn parameter<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next()?.trim();
+        let val = kv.next()?.trim();
+        if key.eq_ignore_ascii_case(name) {
+            Some(val.trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+// Strip the type prefix from a typed field such as "rfc822; bob@example.com"
+fn strip_type(value: &str) -> &str {
+    match value.find(';') {
+        Some(pos) => value[pos + 1..].trim(),
+        None => value.trim(),
+    }
+}
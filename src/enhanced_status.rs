@@ -0,0 +1,75 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// An RFC 3463 enhanced mail system status code, such as `5.1.1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EnhancedStatusCode {
+    /// 2 (success), 4 (persistent transient failure) or 5 (permanent failure)
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+    pub fn new(class: u8, subject: u16, detail: u16) -> EnhancedStatusCode {
+        EnhancedStatusCode { class, subject, detail }
+    }
+
+    /// Find the first enhanced status code embedded in some text, such as an SMTP
+    /// response line ("550 5.1.1 <bob@example.com>: Recipient address rejected")
+    pub fn find_in(text: &str) -> Option<EnhancedStatusCode> {
+        text.split(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter_map(|word| word.parse().ok())
+            .next()
+    }
+
+    /// Whether this is a permanent failure code
+    pub fn is_permanent(&self) -> bool {
+        self.class == 5
+    }
+
+    /// Whether this is a transient failure code
+    pub fn is_transient(&self) -> bool {
+        self.class == 4
+    }
+
+    /// Whether this code says the destination mailbox does not exist
+    /// (`5.1.0` other address status, `5.1.1` bad destination mailbox address)
+    pub fn is_user_unknown(&self) -> bool {
+        self.class == 5 && self.subject == 1 && (self.detail == 0 || self.detail == 1)
+    }
+}
+
+impl FromStr for EnhancedStatusCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EnhancedStatusCode, ()> {
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 3 {
+            return Err(());
+        }
+        let class = match parts[0] {
+            "2" => 2,
+            "4" => 4,
+            "5" => 5,
+            _ => return Err(()),
+        };
+        let number = |p: &str| -> Result<u16, ()> {
+            if p.is_empty() || p.len() > 3 || !p.chars().all(|c| c.is_ascii_digit()) {
+                return Err(());
+            }
+            p.parse().map_err(|_| ())
+        };
+        Ok(EnhancedStatusCode {
+            class,
+            subject: number(parts[1])?,
+            detail: number(parts[2])?,
+        })
+    }
+}
+
+impl fmt::Display for EnhancedStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
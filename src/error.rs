@@ -1,5 +1,5 @@
 use crate::storage::MailstromStorageError;
-use crate::worker::Message;
+use crate::worker::{Message, WorkerStatus};
 use email_format::rfc5322::ParseError;
 use std::convert::From;
 use std::io::Error as IoError;
@@ -15,6 +15,27 @@ pub enum Error {
     Lock,
     Io(IoError),
     LettreEmailAddress(lettre::error::Error),
+    Json(serde_json::Error),
+    InvalidDsn(String),
+    /// The worker thread has stopped (with this status), so the email was stored
+    /// but will not be sent until Mailstrom is restarted
+    WorkerUnavailable(WorkerStatus),
+    InvalidSenderDomain(String),
+    /// A configuration file could not be parsed
+    Config(String),
+    /// `Config::max_queue_depth` emails are already waiting to be delivered
+    QueueFull,
+    /// The worker had `Config::worker_channel_capacity` requests waiting for it
+    /// throughout `Config::worker_send_timeout_ms`.  The email (with this message-id)
+    /// was stored, and the worker will pick it up when it next rereads storage.
+    WorkerBusy(String),
+    /// The message is this many bytes, more than `Config::max_message_bytes` (the
+    /// second)
+    MessageTooLarge(usize, usize),
+    /// The email has no recipients to send it to
+    NoRecipients,
+    /// This recipient address cannot be sent to
+    InvalidRecipient(String),
 }
 
 impl From<SendError<Message>> for Error {
@@ -47,6 +68,12 @@ impl From<IoError> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
 impl ::std::fmt::Display for Error {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match *self {
@@ -58,8 +85,31 @@ impl ::std::fmt::Display for Error {
             Error::Lock => write!(f, "Lock poisoned"),
             Error::Io(ref e) => write!(f, "I/O Error: {}", e),
             Error::LettreEmailAddress(ref e) => write!(f, "Lettre crate Email Address error: {}", e),
+            Error::Json(ref e) => write!(f, "JSON error: {}", e),
+            Error::InvalidDsn(ref s) => write!(f, "Invalid delivery status notification: {}", s),
+            Error::WorkerUnavailable(ref s) => write!(f, "Worker is not running: {:?}", s),
+            Error::InvalidSenderDomain(ref s) => write!(f, "Invalid sender domain: {}", s),
+            Error::Config(ref s) => write!(f, "Invalid configuration: {}", s),
+            Error::QueueFull => write!(f, "Too many emails are waiting to be delivered"),
+            Error::WorkerBusy(ref message_id) =>
+                write!(f, "Worker is busy, email {} will be picked up later", message_id),
+            Error::MessageTooLarge(size, max) =>
+                write!(f, "Message is {} bytes, but at most {} are allowed", size, max),
+            Error::NoRecipients => write!(f, "No recipients"),
+            Error::InvalidRecipient(ref s) => write!(f, "Invalid recipient address: {}", s),
         }
     }
 }
 
-impl ::std::error::Error for Error { }
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match *self {
+            Error::Send(ref e) => Some(e),
+            Error::EmailParser(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::LettreEmailAddress(ref e) => Some(e),
+            Error::Json(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
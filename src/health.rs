@@ -0,0 +1,24 @@
+use crate::worker::WorkerStatus;
+use std::time::SystemTime;
+
+/// A snapshot of how Mailstrom is doing, for readiness and liveness probes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    /// The status of the worker thread
+    pub worker_status: WorkerStatus,
+    /// Whether the storage could be read
+    pub storage_reachable: bool,
+    /// How many emails are waiting to be delivered.  None if the storage could
+    /// not be read.
+    pub queue_depth: Option<usize>,
+    /// When an email was last delivered to a recipient, if one has been since
+    /// Mailstrom started
+    pub last_delivery: Option<SystemTime>,
+}
+
+impl Health {
+    /// Whether the worker is running and the storage can be read
+    pub fn is_healthy(&self) -> bool {
+        self.worker_status == WorkerStatus::Ok && self.storage_reachable
+    }
+}
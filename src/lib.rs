@@ -15,17 +15,37 @@
 //!   [trust-dns](https://github.com/bluejekyll/trust-dns) library for DNS lookups.
 //! * SMTP transport "heavy lifting" is performed via the [lettre](https://github.com/lettre/lettre)
 //!   library.  Uses STARTTLS where available.
-//! * Retries with exponential backoff for a fixed number of retries (currently fixed at 3),
-//!   when the send result is Deferred
+//! * Retries with exponential backoff (or a `RetryPolicy` of your own) for a configurable
+//!   number of retries, when the send result is Deferred
 //! * Uses a pluggable user-defined state management (persistence) layer.
 //!
+//! ## Optional features
+//!
+//! * `metrics`: a Prometheus `DeliveryListener` (see the `metrics` module).
+//! * `tracing`: the worker enters [tracing](https://github.com/tokio-rs/tracing) spans
+//!   carrying the message-id, recipients, MX host and attempt number.  Mailstrom still
+//!   logs through the `log` crate; use `tracing-log` to see those lines within the spans.
+//! * `async`: `AsyncMailstrom`, whose methods return futures that run under any
+//!   runtime (tokio included).
+//! * `dnssec`: lets `RemoteDeliveryConfig::validate_dnssec` validate DNS answers.
+//!
 //! ## Limitations
 //!
 //! * The [email-format](https://github.com/mikedilger/email-format) crate is somewhat incomplete
 //!   and clunky still.  It doesn't incorporate RFC 6854 (updated From and Sender syntax) yet.
 //!   It defines types one-to-one with ABNF parsing units, rather than as semantic units of meaning.
-//!   And it doesn't let you use obvious types yet like setting the date from a `DateTime` type.
+//!   And it doesn't let you use obvious types yet like setting the date from a `DateTime` type
+//!   (use `rfc5322_date` to format a `SystemTime`, or `Mailstrom::send_email_now`).
 //!   However, these issues will be worked out in the near future.
+//! * Nor does it accept internationalized addresses (RFC 6532) in headers.  Send those
+//!   emails with `Mailstrom::send_raw`.  Internationalized domains are sent in ASCII
+//!   (punycode) form, but addresses with a non-ASCII local part are only delivered to
+//!   servers that support SMTPUTF8.
+//! * Deliveries record whether the connection was encrypted
+//!   (`DeliveredResponse::encrypted`), but not the TLS protocol version or cipher
+//!   suite negotiated.  native-tls does not report them, and lettre keeps the stream
+//!   it upgrades with STARTTLS to itself.  All that is known is that the version was
+//!   at least `Config::min_tls_version`.
 //!
 //! You can use it as follows:
 //!
@@ -77,7 +97,9 @@
 //! }
 //! ```
 
+extern crate base64;
 extern crate email_format;
+extern crate idna;
 extern crate lettre;
 extern crate trust_dns_resolver;
 extern crate uuid;
@@ -85,14 +107,19 @@ extern crate uuid;
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate native_tls;
+extern crate rand;
+extern crate socket2;
 extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 #[cfg(test)]
 mod tests;
 
 pub mod config;
-use config::Config;
+use config::{Config, DeliveryConfig, RemoteDeliveryConfig, SendOptions};
 
 mod worker;
 pub use worker::WorkerStatus;
@@ -102,57 +129,125 @@ pub mod error;
 use error::Error;
 
 mod delivery_result;
-pub use delivery_result::DeliveryResult;
+pub use delivery_result::{DeliveredResponse, DeliveryResult};
 
 mod recipient_status;
-pub use recipient_status::RecipientStatus;
+pub use recipient_status::{FailureReason, RecipientStatus};
 
 mod message_status;
-pub use message_status::MessageStatus;
+pub use message_status::{MessageStatus, Outcome};
+
+mod date;
+pub use date::rfc5322_date;
+
+mod enhanced_status;
+pub use enhanced_status::EnhancedStatusCode;
+
+mod suppression;
+pub use suppression::SuppressionList;
+
+pub mod dsn;
+
+mod listener;
+pub use listener::DeliveryListener;
+
+mod health;
+pub use health::Health;
+
+mod message_id;
+pub use message_id::MessageIdGenerator;
+
+mod retry;
+pub use retry::{ExponentialBackoff, RetryContext, RetryPolicy};
+
+mod verp;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncMailstrom;
 
 mod prepared_email;
 
 pub mod storage;
-use storage::MailstromStorage;
+use storage::{InternalMessageStatus, InternalRecipientStatus, MailstromStorage, PreparedEmail};
 
 use email_format::Email;
 use std::ops::Drop;
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::mpsc::{self, SendError, TrySendError};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use trust_dns_resolver::Resolver;
 
 
 pub struct Mailstrom<S: MailstromStorage + 'static> {
     config: Config,
-    sender: mpsc::Sender<Message>,
+    sender: mpsc::SyncSender<Message>,
     worker_status: Arc<RwLock<u8>>,
+    last_delivery: Arc<RwLock<Option<SystemTime>>>,
     storage: Arc<RwLock<S>>,
 }
 
 impl<S: MailstromStorage + 'static> Mailstrom<S> {
     /// Create a new Mailstrom instance for sending emails.
+    ///
+    /// For remote delivery the worker creates its DNS resolver once it is running,
+    /// and stops (with `WorkerStatus::ResolverCreationFailed`) if it cannot.  Use
+    /// `try_new` to find that out before sending anything.
     pub fn new(config: Config, storage: S) -> Mailstrom<S>
     {
-        let (sender, receiver) = mpsc::channel();
+        Mailstrom::with_resolver(config, storage, None)
+    }
+
+    /// Create a new Mailstrom instance for sending emails, creating the DNS resolver
+    /// first for remote delivery.  Returns `Error::DnsUnavailable` if it cannot be
+    /// created (such as if the system DNS configuration cannot be read).
+    pub fn try_new(config: Config, storage: S) -> Result<Mailstrom<S>, Error>
+    {
+        let resolver = match config.delivery {
+            DeliveryConfig::Remote(ref rdc) => match worker::create_resolver(rdc) {
+                Ok(resolver) => Some(resolver),
+                Err(e) => {
+                    error!("Unable to create a DNS resolver: {:?}", e);
+                    return Err(Error::DnsUnavailable);
+                }
+            },
+            _ => None,
+        };
+        Ok(Mailstrom::with_resolver(config, storage, resolver))
+    }
+
+    fn with_resolver(config: Config, storage: S, resolver: Option<Resolver>) -> Mailstrom<S>
+    {
+        let (sender, receiver) = mpsc::sync_channel(config.worker_channel_capacity.max(1));
 
         let storage = Arc::new(RwLock::new(storage));
 
         let worker_status = Arc::new(RwLock::new(WorkerStatus::Ok as u8));
 
+        let last_delivery = Arc::new(RwLock::new(None));
+
         let mut worker = Worker::new(
             receiver,
             Arc::clone(&storage),
             Arc::clone(&worker_status),
+            Arc::clone(&last_delivery),
             config.clone(),
         );
 
         let _ = thread::spawn(move || {
-            worker.run();
+            worker.run(resolver);
         });
 
         Mailstrom {
             config,
             sender,
             worker_status,
+            last_delivery,
             storage,
         }
     }
@@ -160,16 +255,27 @@ impl<S: MailstromStorage + 'static> Mailstrom<S> {
     /// Mailstrom requires an explicit start command to start sending emails.  This is
     /// because some clients are only interested in reading the status of sent emails,
     /// and will terminate before any real sending can be accomplished.
+    ///
+    /// Like sending, this waits at most `Config::worker_send_timeout_ms` for room
+    /// in the worker's channel, returning `Error::Send` if there is none.
     pub fn start(&mut self) -> Result<(), Error> {
-        self.sender.send(Message::Start)?;
-        Ok(())
+        self.send_command(Message::Start)
     }
 
     /// Ask Mailstrom to die.  This is not required, you can simply let it fall out
     /// of scope and it will clean itself up.
+    ///
+    /// Like `start`, this returns `Error::Send` if the worker's channel stays full.
     pub fn die(&mut self) -> Result<(), Error> {
-        self.sender.send(Message::Terminate)?;
-        Ok(())
+        self.send_command(Message::Terminate)
+    }
+
+    fn send_command(&self, message: Message) -> Result<(), Error> {
+        match self.send_to_worker(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(m)) | Err(TrySendError::Disconnected(m)) =>
+                Err(Error::Send(SendError(m))),
+        }
     }
 
     /// Determine the status of the worker
@@ -178,11 +284,92 @@ impl<S: MailstromStorage + 'static> Mailstrom<S> {
         WorkerStatus::from_u8(ws)
     }
 
-    /// Send an email, getting back its message-id
+    /// Check the worker and the storage, for health checks
+    pub fn health(&self) -> Health {
+        let queue_depth = self.queue_depth().ok();
+        Health {
+            worker_status: self.worker_status(),
+            storage_reachable: queue_depth.is_some(),
+            queue_depth,
+            last_delivery: match self.last_delivery.read() {
+                Ok(guard) => *guard,
+                Err(_) => None,
+            },
+        }
+    }
+
+    /// Send an email, getting back its message-id.
+    ///
+    /// The message-id is the email's Message-ID without the angle brackets, or the
+    /// one generated for it if it had none (see `Config::message_id_generator`).
+    /// The email is stored under it before this returns, so `query_status` finds it
+    /// straight away.  The same goes for the other ways of sending.
+    ///
+    /// To resend (forward) an email, add RFC 5322 Resent-* fields to it with
+    /// `Email::add_optional_field`, the latest resending's first: it is then sent
+    /// from the Resent-Sender (or Resent-From) to the Resent-To, Resent-Cc and
+    /// Resent-Bcc recipients of that resending instead of the original ones.
+    ///
+    /// If the worker has stopped, the email is stored but this returns
+    /// `Error::WorkerUnavailable`, since it will not be sent.  If
+    /// `Config::max_queue_depth` emails are already waiting, this returns
+    /// `Error::QueueFull` without storing it.  If the worker is too busy to take it,
+    /// the email is stored and this returns `Error::WorkerBusy` with its message-id.
+    /// An email with no recipients, or with an address that cannot be sent to, is
+    /// refused with `Error::NoRecipients` or `Error::InvalidRecipient`.
     pub fn send_email(&mut self, email: Email) -> Result<String, Error> {
+        self.send_email_with_options(email, SendOptions::default())
+    }
+
+    /// Send an email with some configuration settings overridden (or with an
+    /// idempotency key), getting back its message-id
+    pub fn send_email_with_options(&mut self, email: Email, options: SendOptions)
+                                   -> Result<String, Error>
+    {
+        let (prepared_email, mut internal_message_status) =
+            crate::prepared_email::prepare_email(email, &options.apply(&self.config))?;
+        internal_message_status.options = options;
+
+        self.store_and_send(prepared_email, internal_message_status)
+    }
+
+    /// Send an email, setting its Date to the current time (in UTC), getting back
+    /// its message-id
+    pub fn send_email_now(&mut self, mut email: Email) -> Result<String, Error> {
+        email.set_date(&*rfc5322_date(SystemTime::now(), 0))?;
+        self.send_email(email)
+    }
+
+    /// Send an already-built message (such as MIME built with another crate) to the
+    /// given envelope sender and recipients, bypassing `email_format`.  The message
+    /// must be a complete RFC 5322 message; its Message-ID is used if it has one,
+    /// otherwise one is generated and added.  Returns the message-id.
+    pub fn send_raw(&mut self, from: String, to: Vec<String>, message: Vec<u8>)
+                    -> Result<String, Error>
+    {
         let (prepared_email, internal_message_status) =
-            crate::prepared_email::prepare_email(email, &*self.config.helo_name)?;
+            crate::prepared_email::prepare_raw_email(from, to, message, &self.config)?;
 
+        self.store_and_send(prepared_email, internal_message_status)
+    }
+
+    /// Check an email as `send_email` would (finding its recipients, and checking
+    /// their addresses and the message size) without storing or sending it.
+    /// Returns the status it would start with, every recipient `Queued`.  If the
+    /// email has no Message-ID, the one reported is made up for the check and will
+    /// not be the one it is sent with.
+    pub fn validate_email(&self, email: &Email) -> Result<MessageStatus, Error> {
+        let (_, internal_message_status) =
+            crate::prepared_email::prepare_email(email.clone(), &self.config)?;
+
+        Ok(internal_message_status.as_message_status())
+    }
+
+    fn store_and_send(
+        &mut self,
+        prepared_email: PreparedEmail,
+        internal_message_status: InternalMessageStatus,
+    ) -> Result<String, Error> {
         let message_id = internal_message_status.message_id.clone();
 
         {
@@ -192,17 +379,140 @@ impl<S: MailstromStorage + 'static> Mailstrom<S> {
                 Err(_) => return Err(Error::Lock),
             };
 
+            // An email already sent with the same idempotency key is not sent again
+            if let Some(ref key) = internal_message_status.options.idempotency_key {
+                if let Some(existing) = (*guard).lookup_idempotency_key(key)? {
+                    let submitted_at = (*guard).retrieve_status(&existing)?.submitted_at;
+                    let recent = match SystemTime::now().duration_since(submitted_at) {
+                        Ok(age) => age.as_secs() < self.config.idempotency_window_secs,
+                        Err(_) => true, // the clock went backwards
+                    };
+                    if recent {
+                        info!("Email with idempotency key {} was already sent as {}",
+                              key, existing);
+                        return Ok(existing);
+                    }
+                }
+            }
+
+            // Turn the email away if too many are already waiting
+            if let Some(max) = self.config.max_queue_depth {
+                if (*guard).count_incomplete()? >= max {
+                    return Err(Error::QueueFull);
+                }
+            }
+
             // Store the email
             (*guard).store(prepared_email, internal_message_status)?;
         }
 
-        self.sender.send(Message::SendEmail(message_id.clone()))?;
-
-        info!("Passed email {} off to worker", &*message_id);
+        self.pass_to_worker(&message_id)?;
 
         Ok(message_id)
     }
 
+    /// Try again to deliver an email to every recipient it has not been delivered
+    /// to, such as after retries ran out during an outage.  Those recipients are
+    /// queued afresh (MX records are looked up again) and get a full set of
+    /// attempts.  Recipients it was delivered to are not sent it again.
+    pub fn requeue_email(&self, message_id: &str) -> Result<(), Error> {
+        let requeued = self.requeue(message_id, |recipient| {
+            !matches!(recipient.result, DeliveryResult::Delivered(_))
+        })?;
+        if requeued == 0 {
+            // Delivered to everyone, nothing to do
+            return Ok(());
+        }
+
+        self.pass_to_worker(message_id)
+    }
+
+    /// Try again to deliver an email to one of its recipients (given as the address
+    /// it was sent to), leaving the other recipients as they are.  This queues the
+    /// recipient afresh even if it was delivered to.
+    pub fn requeue_recipient(&self, message_id: &str, recipient: &str) -> Result<(), Error> {
+        let requeued = self.requeue(message_id, |r| {
+            r.email_addr == recipient || r.smtp_email_addr == recipient
+        })?;
+        if requeued == 0 {
+            return Err(Error::General(format!(
+                "Email {} has no recipient {}", message_id, recipient)));
+        }
+
+        self.pass_to_worker(message_id)
+    }
+
+    // Queue the selected recipients of a stored email afresh, giving them a full set
+    // of attempts.  Returns how many recipients were requeued.
+    fn requeue<F>(&self, message_id: &str, select: F) -> Result<usize, Error>
+        where F: Fn(&InternalRecipientStatus) -> bool
+    {
+        // Lock the storage
+        let mut guard = match (*self.storage).write() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        let mut internal_message_status = (*guard).retrieve_status(message_id)?;
+        let mut requeued = 0;
+        for recipient in &mut internal_message_status.recipients {
+            if !select(recipient) {
+                continue;
+            }
+            recipient.result = DeliveryResult::Queued;
+            recipient.failure_reason = None;
+            recipient.delivered_via = None;
+            recipient.mx_servers = None;
+            recipient.mx_source = None;
+            recipient.mx_expires_at = None;
+            recipient.current_mx = 0;
+            recipient.attempts = 0;
+            requeued += 1;
+        }
+        if requeued > 0 {
+            internal_message_status.attempts_remaining =
+                self.config.max_attempts_per_recipient.max(1);
+            internal_message_status.next_retry_at = None;
+            (*guard).update_status(internal_message_status)?;
+        }
+
+        Ok(requeued)
+    }
+
+    // Ask the worker to deliver a stored email
+    fn pass_to_worker(&self, message_id: &str) -> Result<(), Error> {
+        // Don't let the caller think the email is on its way if the worker is gone
+        let worker_status = self.worker_status();
+        if worker_status != WorkerStatus::Ok {
+            return Err(Error::WorkerUnavailable(worker_status));
+        }
+        match self.send_to_worker(Message::SendEmail(message_id.to_owned())) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => return Err(Error::WorkerBusy(message_id.to_owned())),
+            Err(TrySendError::Disconnected(_)) =>
+                return Err(Error::WorkerUnavailable(self.worker_status())),
+        }
+
+        info!("Passed email {} off to worker", message_id);
+
+        Ok(())
+    }
+
+    // Send the worker a message, waiting up to `worker_send_timeout_ms` for room in
+    // the channel
+    fn send_to_worker(&self, mut message: Message) -> Result<(), TrySendError<Message>> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.worker_send_timeout_ms);
+        loop {
+            match self.sender.try_send(message) {
+                Err(TrySendError::Full(m)) if Instant::now() < deadline => {
+                    message = m;
+                    thread::sleep(Duration::from_millis(10));
+                },
+                result => return result,
+            }
+        }
+    }
+
     // Query Status of email
     pub fn query_status(&mut self, message_id: &str) -> Result<MessageStatus, Error> {
         let guard = match (*self.storage).read() {
@@ -227,11 +537,116 @@ impl<S: MailstromStorage + 'static> Mailstrom<S> {
         let vec_statuses = (*guard).retrieve_all_recent()?;
         Ok(vec_statuses.iter().map(|s| s.as_message_status()).collect())
     }
+
+    /// Find which email and recipient a bounce was for, from the address it was sent
+    /// to (when `Config::verp` is set).  Returns the message-id and the recipient's
+    /// address, or None if the address is not one of ours.
+    pub fn decode_verp_address(&self, address: &str) -> Result<Option<(String, String)>, Error> {
+        let verp = match self.config.verp {
+            Some(ref verp) => verp,
+            None => return Ok(None),
+        };
+        let (message_id, index) = match crate::verp::decode_verp_address(verp, address) {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        };
+
+        let guard = match (*self.storage).read() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        let status = (*guard).retrieve_status(&message_id)?;
+        Ok(status.recipients.get(index).map(|r| (message_id, r.smtp_email_addr.clone())))
+    }
+
+    /// Look up the servers that email to `domain` would be delivered to, in the order
+    /// they would be tried, as remote delivery would (see
+    /// `RemoteDeliveryConfig::mx_overrides`).  This creates a resolver of its own from
+    /// the configuration, and is meant for diagnosing delivery problems.
+    pub fn lookup_mx(&self, domain: &str) -> Result<Vec<String>, Error> {
+        let rdc = match self.config.delivery {
+            DeliveryConfig::Remote(ref rdc) => rdc.clone(),
+            _ => RemoteDeliveryConfig::default(),
+        };
+        let resolver = match worker::create_resolver(&rdc) {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!("Unable to create a DNS resolver: {:?}", e);
+                return Err(Error::DnsUnavailable);
+            }
+        };
+
+        let (servers, _, _) = worker::mx::get_mx_records_for_domain(domain, &resolver, &rdc)?;
+        Ok(servers)
+    }
+
+    /// Find the email a bounce is about, from its raw message: by the envelope id it
+    /// reports if it is a DSN, or else the Message-ID of the original headers it
+    /// quotes (see `dsn::bounced_message_ids`).  Returns None if it names no
+    /// message; if it does but none of them is stored, this is the storage error
+    /// (as with `query_status`).
+    pub fn find_bounced_email(&self, raw: &[u8]) -> Result<Option<MessageStatus>, Error> {
+        let guard = match (*self.storage).read() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        let mut not_found: Option<Error> = None;
+        for message_id in crate::dsn::bounced_message_ids(raw) {
+            match (*guard).retrieve_status(&message_id) {
+                Ok(status) => return Ok(Some(status.as_message_status())),
+                Err(e) => not_found = Some(e.into()),
+            }
+        }
+        match not_found {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// How many emails are waiting to be delivered (to at least one recipient)
+    pub fn queue_depth(&self) -> Result<usize, Error> {
+        let guard = match (*self.storage).read() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        Ok((*guard).count_incomplete()?)
+    }
+
+    /// Query all emails that could not be delivered to one or more recipients
+    /// (because a server rejected them, or the retries ran out), for review.  Unlike
+    /// `query_recent`, this reports them every time it is called.
+    pub fn query_dead_letters(&mut self) -> Result<Vec<MessageStatus>, Error> {
+        let guard = match (*self.storage).read() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        let vec_statuses = (*guard).retrieve_failed()?;
+        Ok(vec_statuses.iter().map(|s| s.as_message_status()).collect())
+    }
+
+    /// Query a page of all the stored emails, complete or not, oldest submitted
+    /// first: up to `limit` of them, after skipping `offset`.  Unlike `query_recent`,
+    /// this does not affect what later queries report.
+    pub fn query_page(&self, offset: usize, limit: usize) -> Result<Vec<MessageStatus>, Error> {
+        let guard = match (*self.storage).read() {
+            Ok(guard) => guard,
+            Err(_) => return Err(Error::Lock),
+        };
+
+        let vec_statuses = (*guard).retrieve_page(offset, limit)?;
+        Ok(vec_statuses.iter().map(|s| s.as_message_status()).collect())
+    }
 }
 
 impl<S: MailstromStorage + 'static> Drop for Mailstrom<S> {
     fn drop(&mut self) {
         info!("Mailstrom is terminating.");
-        let _ = self.sender.send(Message::Terminate);
+        // Don't hang if the channel is full.  The worker stops anyway once it has
+        // drained the channel and finds it disconnected.
+        let _ = self.send_to_worker(Message::Terminate);
     }
 }
@@ -0,0 +1,21 @@
+use crate::delivery_result::DeliveryResult;
+use std::fmt;
+
+/// A hook for observing deliveries as they happen, e.g. for metrics or tracing.
+///
+/// Set one in `Config::listener`.  The methods are called from the worker thread, so
+/// they should return quickly.
+pub trait DeliveryListener: Send + Sync {
+    /// Called just before an SMTP delivery of the message to this recipient is
+    /// attempted at the given MX server (or relay)
+    fn on_attempt(&self, message_id: &str, recipient: &str, mx: &str);
+
+    /// Called with the result for this recipient once a delivery attempt is over
+    fn on_result(&self, message_id: &str, recipient: &str, result: &DeliveryResult);
+}
+
+impl fmt::Debug for dyn DeliveryListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeliveryListener")
+    }
+}
@@ -0,0 +1,36 @@
+use crate::config::Config;
+use std::fmt;
+use uuid::Uuid;
+
+/// A source of message-ids for emails that do not already have one.
+///
+/// Set one in `Config::message_id_generator`.  It must return an id in the form
+/// `left@right` (without angle brackets) that parses as an RFC 5322 msg-id, and it
+/// must be unique, since message-ids are also used to look up delivery status.
+///
+/// This is implemented for closures, so `Arc::new(|| ...)` will do.
+pub trait MessageIdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> MessageIdGenerator for F {
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn MessageIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageIdGenerator")
+    }
+}
+
+// Generate a message-id per the configuration: the user's generator if there is
+// one, otherwise `uuid@domain`
+pub fn generate_message_id(config: &Config) -> String {
+    if let Some(ref generator) = config.message_id_generator {
+        return generator.generate();
+    }
+    let domain = config.message_id_domain.as_ref().unwrap_or(&config.helo_name);
+    format!("{}@{}", Uuid::new_v4().hyphenated(), domain)
+}
@@ -1,5 +1,8 @@
+use crate::config::SendOptions;
 use crate::delivery_result::DeliveryResult;
+use crate::error::Error;
 use crate::recipient_status::{InternalRecipientStatus, RecipientStatus};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// An email to be sent (internal format).  This is exposed publicly for
 /// implementers of `MailstromStorage` but otherwise should not
@@ -14,13 +17,31 @@ pub struct InternalMessageStatus {
     /// (MX record lookups take some time).
     pub recipients: Vec<InternalRecipientStatus>,
 
-    /// Attempts remaining. This counts backwards to zero. If all deliveries are
-    /// complete (permanent success or failure), it is set to zero.
+    /// Attempts remaining: the most that any recipient still to be delivered to has
+    /// left.  If all deliveries are complete (permanent success or failure), it is
+    /// set to zero.
     ///
-    /// Per-recipient deferred attempt numbers count upwards, and may get more
-    /// attempts because a single worker pass may try a recipient on muliple MX
-    /// servers.
+    /// Each recipient's attempts (`InternalRecipientStatus::attempts`) count upwards,
+    /// once per worker pass (however many MX servers the pass tried), up to
+    /// `Config::max_attempts_per_recipient`.
     pub attempts_remaining: u8,
+
+    /// Overrides of the configuration for this email
+    #[serde(default)]
+    pub options: SendOptions,
+
+    /// When the worker has scheduled the next delivery attempt, if it has
+    #[serde(default)]
+    pub next_retry_at: Option<SystemTime>,
+
+    /// When the email was submitted (the Unix epoch for emails stored by versions
+    /// that did not record it)
+    #[serde(default = "unknown_submission_time")]
+    pub submitted_at: SystemTime,
+}
+
+fn unknown_submission_time() -> SystemTime {
+    UNIX_EPOCH
 }
 
 impl InternalMessageStatus {
@@ -29,19 +50,49 @@ impl InternalMessageStatus {
             message_id: self.message_id.clone(),
             recipient_status: self.recipients
                 .iter()
-                .map(|r| RecipientStatus {
-                    recipient: r.email_addr.clone(),
-                    result: r.result.clone(),
-                })
+                .map(|r| r.as_recipient_status())
                 .collect(),
+            attempts_remaining: self.attempts_remaining,
+            next_retry_at: self.next_retry_at,
+            submitted_at: self.submitted_at,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The overall outcome of sending an email
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Delivery to some recipients is not yet complete
+    InProgress,
+    /// Delivered to every recipient
+    AllDelivered,
+    /// Delivered to some recipients, but failed for others
+    PartialFailure,
+    /// Failed for every recipient
+    AllFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MessageStatus {
     pub message_id: String,
+    /// One entry per recipient, in a stable order: the To recipients, then Cc, then
+    /// Bcc, each in the order they were given (for `send_raw`, the order of `to`).
+    /// A recipient given more than once appears only where they were first given.
+    /// For a resent email, these are the Resent-To, Resent-Cc and Resent-Bcc
+    /// recipients of its latest resending instead.
     pub recipient_status: Vec<RecipientStatus>,
+    /// How many more times the worker will try to deliver to deferred recipients
+    /// (the most that any of them has left).  Zero once delivery is complete, or
+    /// when the next failure is final.
+    #[serde(default)]
+    pub attempts_remaining: u8,
+    /// When the worker will next try to deliver to deferred recipients, or None if
+    /// no retry is scheduled.
+    #[serde(default)]
+    pub next_retry_at: Option<SystemTime>,
+    /// When the email was given to Mailstrom (the Unix epoch if that is not known)
+    #[serde(default = "unknown_submission_time")]
+    pub submitted_at: SystemTime,
 }
 
 impl MessageStatus {
@@ -55,4 +106,29 @@ impl MessageStatus {
     pub fn completed(&self) -> bool {
         self.recipient_status.iter().all(|r| r.result.completed())
     }
+
+    pub fn outcome(&self) -> Outcome {
+        if !self.completed() {
+            Outcome::InProgress
+        } else if self.succeeded() {
+            Outcome::AllDelivered
+        } else if self.recipient_status
+            .iter()
+            .any(|r| matches!(r.result, DeliveryResult::Delivered(_)))
+        {
+            Outcome::PartialFailure
+        } else {
+            Outcome::AllFailed
+        }
+    }
+
+    /// Serialize this status as JSON
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Deserialize a status from JSON produced by `to_json`
+    pub fn from_json(json: &str) -> Result<MessageStatus, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
 }
@@ -0,0 +1,138 @@
+//! Prometheus metrics (requires the `metrics` feature).
+//!
+//! Put a `PrometheusListener` in `Config::listener` (keeping a clone of the `Arc`),
+//! and serve the output of `PrometheusListener::render` from your `/metrics`
+//! endpoint.
+//!
+//! Note that a listener only sees delivery attempts, so messages that are queued but
+//! not yet attempted are not counted here.
+
+use crate::delivery_result::DeliveryResult;
+use crate::listener::DeliveryListener;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds (in seconds) of the delivery latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+struct Histogram {
+    // Cumulative counts, one per bucket in LATENCY_BUCKETS
+    buckets: [u64; 10],
+    count: u64,
+    sum: f64,
+}
+
+/// A `DeliveryListener` that keeps Prometheus counters and a delivery latency
+/// histogram
+pub struct PrometheusListener {
+    attempts: AtomicU64,
+    queued: AtomicU64,
+    delivered: AtomicU64,
+    deferred: AtomicU64,
+    failed: AtomicU64,
+    latency: Mutex<Histogram>,
+    // Start time of attempts in progress, by (message_id, recipient)
+    started: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Default for PrometheusListener {
+    fn default() -> PrometheusListener {
+        PrometheusListener::new()
+    }
+}
+
+impl PrometheusListener {
+    pub fn new() -> PrometheusListener {
+        PrometheusListener {
+            attempts: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            deferred: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            latency: Mutex::new(Histogram {
+                buckets: [0; 10],
+                count: 0,
+                sum: 0.0,
+            }),
+            started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP mailstrom_delivery_attempts_total \
+                               SMTP delivery attempts, per recipient.");
+        let _ = writeln!(out, "# TYPE mailstrom_delivery_attempts_total counter");
+        let _ = writeln!(out, "mailstrom_delivery_attempts_total {}",
+                         self.attempts.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP mailstrom_delivery_results_total \
+                               Delivery attempt results, per recipient.");
+        let _ = writeln!(out, "# TYPE mailstrom_delivery_results_total counter");
+        for (label, counter) in &[
+            ("queued", &self.queued),
+            ("delivered", &self.delivered),
+            ("deferred", &self.deferred),
+            ("failed", &self.failed),
+        ] {
+            let _ = writeln!(out, "mailstrom_delivery_results_total{{result=\"{}\"}} {}",
+                             label, counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(out, "# HELP mailstrom_delivery_latency_seconds \
+                               Time taken by delivery attempts.");
+        let _ = writeln!(out, "# TYPE mailstrom_delivery_latency_seconds histogram");
+        if let Ok(histogram) = self.latency.lock() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(out, "mailstrom_delivery_latency_seconds_bucket{{le=\"{}\"}} {}",
+                                 bound, count);
+            }
+            let _ = writeln!(out, "mailstrom_delivery_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+                             histogram.count);
+            let _ = writeln!(out, "mailstrom_delivery_latency_seconds_sum {}", histogram.sum);
+            let _ = writeln!(out, "mailstrom_delivery_latency_seconds_count {}", histogram.count);
+        }
+
+        out
+    }
+}
+
+impl DeliveryListener for PrometheusListener {
+    fn on_attempt(&self, message_id: &str, recipient: &str, _mx: &str) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut started) = self.started.lock() {
+            started.insert((message_id.to_owned(), recipient.to_owned()), Instant::now());
+        }
+    }
+
+    fn on_result(&self, message_id: &str, recipient: &str, result: &DeliveryResult) {
+        let counter = match *result {
+            DeliveryResult::Queued => &self.queued,
+            DeliveryResult::Deferred(_, _) => &self.deferred,
+            DeliveryResult::Delivered(_) => &self.delivered,
+            DeliveryResult::Failed(_) => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        // Results without an attempt (e.g. suppressed recipients) have no latency
+        let start = match self.started.lock() {
+            Ok(mut started) => started.remove(&(message_id.to_owned(), recipient.to_owned())),
+            Err(_) => None,
+        };
+        if let (Some(start), Ok(mut histogram)) = (start, self.latency.lock()) {
+            let secs = start.elapsed().as_secs_f64();
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+                if secs <= *bound {
+                    *count += 1;
+                }
+            }
+            histogram.count += 1;
+            histogram.sum += secs;
+        }
+    }
+}
@@ -1,14 +1,25 @@
+use crate::config::{Config, DeliveryConfig, RemoteDeliveryConfig, SendOptions};
 use crate::delivery_result::DeliveryResult;
-use email_format::rfc5322::headers::Bcc;
-use email_format::rfc5322::types::{Address, GroupList, Mailbox};
-use email_format::Email;
+use email_format::rfc5322::headers::{
+    Bcc, MessageId, ResentBcc, ResentCc, ResentFrom, ResentSender, ResentTo
+};
+use email_format::rfc5322::types::{Address, AddrSpec, GroupList, Mailbox};
+use email_format::{Email, TryFrom};
 use crate::error::Error;
 use lettre::{EmailAddress, SendableEmail, Envelope};
+use crate::message_id::generate_message_id;
 use crate::message_status::InternalMessageStatus;
-use crate::recipient_status::InternalRecipientStatus;
-use uuid::Uuid;
+use crate::recipient_status::{FailureReason, InternalRecipientStatus};
+use crate::worker::create_resolver;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::SystemTime;
 
 /// An email, prepared for delivery.
+///
+/// `to` is the envelope, listing every recipient.  Each delivery sends `message`
+/// unchanged to just the recipients it is for, so the headers of a retried message
+/// still show everyone it was addressed to.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PreparedEmail {
     pub to: Vec<String>,
@@ -20,12 +31,12 @@ pub struct PreparedEmail {
 impl PreparedEmail {
     pub fn as_sendable_email(&self) -> Result<SendableEmail, lettre::error::Error> {
         let to: Result<Vec<EmailAddress>, lettre::error::Error> =
-            self.to.iter().map(|s| EmailAddress::new(s.clone())).collect();
+            self.to.iter().map(|s| email_address(s)).collect();
         let to = to?;
 
         Ok(SendableEmail::new(
             Envelope::new(
-                Some(EmailAddress::new(self.from.clone())?),
+                Some(email_address(&self.from)?),
                 to)?,
             self.message_id.clone(),
             self.message.clone()
@@ -35,48 +46,317 @@ impl PreparedEmail {
 
 pub fn prepare_email(
     mut email: Email,
-    helo_name: &str,
+    config: &Config,
 ) -> Result<(PreparedEmail, InternalMessageStatus), Error> {
-    let recipients = determine_recipients(&email);
+    // A resent message goes to the recipients of its latest resending, from
+    // whoever resent it
+    let resent = latest_resent_block(&email)?;
+    let mut recipients = match resent {
+        Some(ref resent) => recipients_from_addresses(resent.addresses.clone()),
+        None => determine_recipients(&email),
+    };
+    let resent_sender = resent.and_then(|resent| resent.sender);
+    if recipients.is_empty() {
+        return Err(Error::NoRecipients);
+    }
+
+    if config.check_sender_domain {
+        let domain = match resent_sender {
+            Some(ref mailbox) => mailbox_domain(mailbox),
+            None => sender_domain(&email),
+        };
+        check_sender_domain(&domain, config)?;
+    }
 
-    // Blind the Bcc
+    if config.undisclosed_recipients && email.get_to().is_none() && email.get_cc().is_none() {
+        email.set_to("undisclosed-recipients:;")?;
+    }
+
+    // Blind the Bcc (and any Resent-Bcc)
     email.clear_bcc();
+    clear_resent_bcc(&mut email)?;
 
     let message_id = match email.get_message_id() {
         Some(mid) => format!("{}@{}", mid.0.id_left, mid.0.id_right),
         None => {
-            // Generate message-id
-            let message_id = format!("{}@{}", Uuid::new_v4().hyphenated().to_string(), helo_name);
-            email.set_message_id(&*format!("<{}>", message_id))?;
+            // Generate message-id, and read it back as it parsed so that it matches
+            // what we report for existing message-ids
+            email.set_message_id(&*format!("<{}>", generate_message_id(config)))?;
+            match email.get_message_id() {
+                Some(mid) => format!("{}@{}", mid.0.id_left, mid.0.id_right),
+                None => return Err(Error::General("Message-ID was not set".to_owned())),
+            }
+        }
+    };
+
+    let from = match config.bounce_address {
+        Some(ref address) => address.trim().to_owned(),
+        None => match resent_sender {
+            Some(ref mailbox) => format!("{}", addr_spec(mailbox)).trim().to_owned(),
+            None => format!("{}", email.get_from().0),
+        },
+    };
+
+    // Tell the recipient where bounces go
+    let mut message = format!("Return-Path: <{}>\r\n", from).into_bytes();
+    if let Some(ref address) = config.redirect_all_to {
+        message.append(&mut redirect_recipients(&mut recipients, address)?);
+    }
+    message.extend_from_slice(format!("{}", email).as_bytes());
+
+    // Only the envelope sender is overridden
+    let from = match config.envelope_from_override {
+        Some(ref address) => address.trim().to_owned(),
+        None => from,
+    };
+
+    let prepared_email = PreparedEmail {
+        to: recipients
+            .iter()
+            .map(|r| r.smtp_email_addr.clone())
+            .collect(),
+        from: envelope_address(&from),
+        message_id: message_id.clone(),
+        message,
+    };
+    check_message_size(&prepared_email, config)?;
+    check_recipient_domains(&mut recipients, config);
+
+    // Verify that lettre::SendableEmail will not give us errors later on
+    // down the track
+    let _ = email_address(&prepared_email.from)?;
+    prepared_email.to.iter()
+        .try_for_each(|s| email_address(s).map(|_|()).map_err(|_| {
+            Error::InvalidRecipient(s.clone())
+        }))?;
+
+    let internal_message_status = InternalMessageStatus {
+        message_id,
+        recipients,
+        attempts_remaining: config.max_attempts_per_recipient.max(1),
+        options: SendOptions::default(),
+        next_retry_at: None,
+        submitted_at: SystemTime::now(),
+    };
+
+    Ok((prepared_email, internal_message_status))
+}
+
+// Prepare an already-built message for delivery to the given envelope sender and
+// recipients.  The message is sent as-is, except that a Message-ID header is added if
+// it does not have one.
+pub fn prepare_raw_email(
+    from: String,
+    to: Vec<String>,
+    mut message: Vec<u8>,
+    config: &Config,
+) -> Result<(PreparedEmail, InternalMessageStatus), Error> {
+    if to.is_empty() {
+        return Err(Error::NoRecipients);
+    }
+
+    if config.check_sender_domain {
+        let domain = match from.rfind('@') {
+            Some(at) => &from[at + 1..],
+            None => "",
+        };
+        check_sender_domain(domain.trim(), config)?;
+    }
+
+    let mut recipients: Vec<InternalRecipientStatus> = Vec::new();
+    for address in &to {
+        let address = address.trim();
+        let domain = match address.rfind('@') {
+            Some(at) => &address[at + 1..],
+            None => return Err(Error::InvalidRecipient(address.to_owned())),
+        };
+        recipients.push(new_recipient(address, address, domain));
+    }
+    dedup_recipients(&mut recipients);
+
+    let message_id = match find_message_id(&message) {
+        Some(mid) => mid,
+        None => {
+            // Generate message-id, checking that it parses
+            let mid: MessageId = TryFrom::try_from(&*format!("<{}>", generate_message_id(config)))?;
+            let message_id = format!("{}@{}", mid.0.id_left, mid.0.id_right);
+            let mut with_header = format!("Message-ID: <{}>\r\n", message_id).into_bytes();
+            with_header.append(&mut message);
+            message = with_header;
             message_id
         }
     };
 
+    if let Some(ref address) = config.redirect_all_to {
+        let mut with_headers = redirect_recipients(&mut recipients, address)?;
+        with_headers.append(&mut message);
+        message = with_headers;
+    }
+
     let prepared_email = PreparedEmail {
         to: recipients
             .iter()
             .map(|r| r.smtp_email_addr.clone())
             .collect(),
-        from: format!("{}", email.get_from().0),
+        from: envelope_address(from.trim()),
         message_id: message_id.clone(),
-        message: format!("{}", email).into_bytes(),
+        message,
     };
+    check_message_size(&prepared_email, config)?;
+    check_recipient_domains(&mut recipients, config);
 
     // Verify that lettre::SendableEmail will not give us errors later on
     // down the track
-    let _ = ::lettre::EmailAddress::new(prepared_email.from.clone())?;
+    let _ = email_address(&prepared_email.from)?;
     prepared_email.to.iter()
-        .try_for_each(|s| ::lettre::EmailAddress::new(s.clone()).map(|_|()))?;
+        .try_for_each(|s| email_address(s).map(|_|()).map_err(|_| {
+            Error::InvalidRecipient(s.clone())
+        }))?;
 
     let internal_message_status = InternalMessageStatus {
         message_id,
         recipients,
-        attempts_remaining: 3,
+        attempts_remaining: config.max_attempts_per_recipient.max(1),
+        options: SendOptions::default(),
+        next_retry_at: None,
+        submitted_at: SystemTime::now(),
     };
 
     Ok((prepared_email, internal_message_status))
 }
 
+// Replace the recipients with `address` (Config::redirect_all_to), returning
+// X-Original-To headers that list who they were
+fn redirect_recipients(
+    recipients: &mut Vec<InternalRecipientStatus>,
+    address: &str,
+) -> Result<Vec<u8>, Error> {
+    let address = address.trim();
+    let domain = match address.rfind('@') {
+        Some(at) => &address[at + 1..],
+        None => return Err(Error::General(format!("Invalid redirect address: {}", address))),
+    };
+    let mut headers: Vec<u8> = Vec::new();
+    for r in recipients.iter() {
+        headers.extend_from_slice(format!("X-Original-To: {}\r\n", r.smtp_email_addr).as_bytes());
+    }
+    *recipients = vec![new_recipient(address, address, domain)];
+    Ok(headers)
+}
+
+// Fail any recipients at domains that Config::allowed_domains or denied_domains
+// do not permit
+fn check_recipient_domains(recipients: &mut [InternalRecipientStatus], config: &Config) {
+    let normalize = |domain: &str| {
+        domain_to_ascii(domain.trim()).trim_end_matches('.').to_lowercase()
+    };
+    let denied: HashSet<String> = config.denied_domains.iter().map(|d| normalize(d)).collect();
+    let allowed: Option<HashSet<String>> = config.allowed_domains.as_ref()
+        .map(|domains| domains.iter().map(|d| normalize(d)).collect());
+    for recip in recipients.iter_mut() {
+        let domain = normalize(&recip.domain);
+        let permitted = !denied.contains(&domain) && match allowed {
+            Some(ref allowed) => allowed.contains(&domain),
+            None => true,
+        };
+        if !permitted {
+            recip.fail(FailureReason::DomainNotPermitted,
+                       format!("Domain not permitted: {}", recip.domain));
+        }
+    }
+}
+
+// Find the Message-ID in the header section of a raw message (without the angle
+// brackets)
+fn find_message_id(message: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(message);
+    let mut lines = text.split('\n').map(|line| line.trim_end_matches('\r'));
+    while let Some(line) = lines.next() {
+        if line.is_empty() {
+            // End of the headers
+            return None;
+        }
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        if !line[..colon].trim().eq_ignore_ascii_case("message-id") {
+            continue;
+        }
+        // The value may be folded onto the following line
+        let mut value = line[colon + 1..].trim().to_owned();
+        if value.is_empty() {
+            value = lines.next().unwrap_or("").trim().to_owned();
+        }
+        let value = value.trim_start_matches('<').trim_end_matches('>').trim();
+        return if value.is_empty() { None } else { Some(value.to_owned()) };
+    }
+    None
+}
+
+// The most recent block of Resent-* fields (RFC 5322 section 3.6.6)
+struct ResentBlock {
+    // Resent-Sender, or else the first Resent-From
+    sender: Option<Mailbox>,
+    // Resent-To, then Resent-Cc, then Resent-Bcc
+    addresses: Vec<Address>,
+}
+
+// Find the fields of the latest resending of the email, if it has been resent.
+// email_format only has them as optional fields.  Each resending adds its block
+// above those of earlier ones, so the first block is the latest.
+fn latest_resent_block(email: &Email) -> Result<Option<ResentBlock>, Error> {
+    let fields: Vec<(String, String)> = email.get_optional_fields()
+        .iter()
+        .map(|field| (format!("{}", field.name).to_lowercase(),
+                      format!("{}", field.value).trim().to_owned()))
+        .skip_while(|(name, _)| !name.starts_with("resent-"))
+        .take_while(|(name, _)| name.starts_with("resent-"))
+        .collect();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let (mut sender, mut from) = (None, None);
+    let (mut to, mut cc, mut bcc) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, value) in &fields {
+        match &**name {
+            "resent-sender" => sender = Some(ResentSender::try_from(&**value)?.0),
+            "resent-from" if from.is_none() => {
+                from = (ResentFrom::try_from(&**value)?.0).0.into_iter().next();
+            }
+            "resent-to" => to.extend((ResentTo::try_from(&**value)?.0).0),
+            "resent-cc" => cc.extend((ResentCc::try_from(&**value)?.0).0),
+            "resent-bcc" => {
+                if let ResentBcc::AddressList(al) = ResentBcc::try_from(&**value)? {
+                    bcc.extend(al.0);
+                }
+            }
+            _ => {}
+        }
+    }
+    to.append(&mut cc);
+    to.append(&mut bcc);
+    Ok(Some(ResentBlock { sender: sender.or(from), addresses: to }))
+}
+
+// Remove any Resent-Bcc fields, keeping the other optional fields in order
+fn clear_resent_bcc(email: &mut Email) -> Result<(), Error> {
+    let fields = email.get_optional_fields();
+    let is_resent_bcc = |name: &str| name.eq_ignore_ascii_case("resent-bcc");
+    if !fields.iter().any(|field| is_resent_bcc(&format!("{}", field.name))) {
+        return Ok(());
+    }
+    email.clear_optional_fields();
+    for field in fields {
+        let name = format!("{}", field.name);
+        if !is_resent_bcc(&name) {
+            email.add_optional_field((&*name, &*format!("{}", field.value)))?;
+        }
+    }
+    Ok(())
+}
+
 fn determine_recipients(email: &Email) -> Vec<InternalRecipientStatus> {
     let mut addresses: Vec<Address> = Vec::new();
 
@@ -92,6 +372,10 @@ fn determine_recipients(email: &Email) -> Vec<InternalRecipientStatus> {
         }
     }
 
+    recipients_from_addresses(addresses)
+}
+
+fn recipients_from_addresses(mut addresses: Vec<Address>) -> Vec<InternalRecipientStatus> {
     addresses.dedup();
 
     let mut recipients: Vec<InternalRecipientStatus> = Vec::new();
@@ -116,9 +400,137 @@ fn determine_recipients(email: &Email) -> Vec<InternalRecipientStatus> {
         }
     }
 
+    dedup_recipients(&mut recipients);
+
     recipients
 }
 
+// Drop repeated recipients, which may differ only in the case of their domain
+// (or appear in different headers).  The first of each is kept, and the order is
+// preserved, since `MessageStatus::recipient_status` promises it.
+fn dedup_recipients(recipients: &mut Vec<InternalRecipientStatus>) {
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    recipients.retain(|r| {
+        let local_part = match r.smtp_email_addr.rfind('@') {
+            Some(at) => &r.smtp_email_addr[..at],
+            None => &*r.smtp_email_addr,
+        };
+        seen.insert((local_part.to_owned(), r.domain.clone()))
+    });
+}
+
+// The domain of the Sender (or else the first From) address
+fn sender_domain(email: &Email) -> String {
+    let mailbox = match email.get_sender() {
+        Some(sender) => sender.0,
+        None => match (email.get_from().0).0.into_iter().next() {
+            Some(mb) => mb,
+            None => return String::new(),
+        },
+    };
+    mailbox_domain(&mailbox)
+}
+
+fn mailbox_domain(mailbox: &Mailbox) -> String {
+    format!("{}", addr_spec(mailbox).domain).trim().to_owned()
+}
+
+fn addr_spec(mailbox: &Mailbox) -> &AddrSpec {
+    match *mailbox {
+        Mailbox::NameAddr(ref na) => &na.angle_addr.addr_spec,
+        Mailbox::AddrSpec(ref ads) => ads,
+    }
+}
+
+// Check that a message is not larger than `Config::max_message_bytes`
+fn check_message_size(prepared_email: &PreparedEmail, config: &Config) -> Result<(), Error> {
+    match config.max_message_bytes {
+        Some(max) if prepared_email.message.len() > max =>
+            Err(Error::MessageTooLarge(prepared_email.message.len(), max)),
+        _ => Ok(()),
+    }
+}
+
+// Check that a sender domain is a valid domain name (or address literal), and if
+// configured, that it resolves
+fn check_sender_domain(domain: &str, config: &Config) -> Result<(), Error> {
+    let invalid = |why: &str| Err(Error::InvalidSenderDomain(format!("{}: {}", domain, why)));
+
+    // Address literals, e.g. [192.0.2.1] or [IPv6:2001:db8::1]
+    if domain.starts_with('[') && domain.ends_with(']') {
+        let literal = &domain[1..domain.len() - 1];
+        let ip = literal.strip_prefix("IPv6:").unwrap_or(literal);
+        return match ip.parse::<IpAddr>() {
+            Ok(_) => Ok(()),
+            Err(_) => invalid("not a valid address literal"),
+        };
+    }
+
+    let ascii = domain_to_ascii(domain);
+    let ascii = ascii.trim_end_matches('.');
+    if ascii.is_empty() {
+        return invalid("empty domain");
+    }
+    if ascii.len() > 253 {
+        return invalid("domain is too long");
+    }
+    for label in ascii.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return invalid("labels must be 1 to 63 characters");
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return invalid("labels may only contain letters, digits and hyphens");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return invalid("labels may not start or end with a hyphen");
+        }
+    }
+
+    if config.resolve_sender_domain {
+        let rdc = match config.delivery {
+            DeliveryConfig::Remote(ref rdc) => rdc.clone(),
+            _ => RemoteDeliveryConfig::default(),
+        };
+        let resolver = match create_resolver(&rdc) {
+            Ok(resolver) => resolver,
+            Err(_) => return Err(Error::DnsUnavailable),
+        };
+        let has_mx = resolver.mx_lookup(ascii)
+            .map(|response| response.iter().next().is_some())
+            .unwrap_or(false);
+        if !has_mx {
+            let has_address = resolver.lookup_ip(ascii)
+                .map(|response| response.iter().next().is_some())
+                .unwrap_or(false);
+            if !has_address {
+                return invalid("no MX or A/AAAA records found");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Convert an address for the envelope.  lettre only accepts ASCII addresses, but
+// internationalized ones (RFC 6531) are fine if they would otherwise be valid; they
+// can be sent to servers that support SMTPUTF8.
+pub(crate) fn email_address(address: &str) -> Result<EmailAddress, lettre::error::Error> {
+    if address.is_ascii() {
+        return EmailAddress::new(address.to_owned());
+    }
+
+    // Check it with ASCII standing in for the local part's other characters
+    let at = address.rfind('@').ok_or(lettre::error::Error::InvalidEmailAddress)?;
+    let local_part: String = address[..at].chars()
+        .map(|c| if c.is_ascii() { c } else { 'x' })
+        .collect();
+    EmailAddress::new(format!("{}@{}", local_part, domain_to_ascii(&address[at + 1..])))?;
+
+    // EmailAddress::new() would refuse it, but deserializing does not check
+    ::serde_json::from_value(::serde_json::Value::String(address.to_owned()))
+        .map_err(|_| lettre::error::Error::InvalidEmailAddress)
+}
+
 fn recipient_from_mailbox(mb: Mailbox) -> InternalRecipientStatus {
     let (email_addr, smtp_email_addr, domain) = match mb {
         Mailbox::NameAddr(na) => (
@@ -133,12 +545,44 @@ fn recipient_from_mailbox(mb: Mailbox) -> InternalRecipientStatus {
         ),
     };
 
+    new_recipient(&email_addr, &smtp_email_addr, &domain)
+}
+
+fn new_recipient(email_addr: &str, smtp_email_addr: &str, domain: &str) -> InternalRecipientStatus {
     InternalRecipientStatus {
         email_addr: email_addr.trim().to_owned(),
-        smtp_email_addr: smtp_email_addr.trim().to_owned(),
-        domain: domain.trim().to_owned(),
+        smtp_email_addr: envelope_address(smtp_email_addr.trim()),
+        // Domains are case-insensitive; local parts are not (RFC 5321 section 2.4)
+        domain: domain_to_ascii(domain.trim()).to_lowercase(),
         mx_servers: None, // To be determined later by a worker task
+        mx_source: None,
+        mx_expires_at: None,
         current_mx: 0,
+        attempts: 0,
         result: DeliveryResult::Queued,
+        failure_reason: None,
+        delivered_via: None,
+    }
+}
+
+// Put an address's domain in ASCII form for the envelope, so that only a non-ASCII
+// local part needs SMTPUTF8. ASCII domains are left as they are.
+pub(crate) fn envelope_address(address: &str) -> String {
+    match address.rfind('@') {
+        Some(at) if !address[at + 1..].is_ascii() =>
+            format!("{}@{}", &address[..at], domain_to_ascii(&address[at + 1..])),
+        _ => address.to_owned(),
+    }
+}
+
+// Convert an internationalized domain name to its ASCII (punycode) form for DNS
+// and SMTP. Domains that fail conversion are returned unchanged.
+pub(crate) fn domain_to_ascii(domain: &str) -> String {
+    match idna::domain_to_ascii(domain) {
+        Ok(ascii) => ascii,
+        Err(e) => {
+            warn!("Could not convert domain {} to ASCII: {:?}", domain, e);
+            domain.to_owned()
+        }
     }
 }
@@ -1,4 +1,45 @@
 use crate::delivery_result::DeliveryResult;
+use std::time::SystemTime;
+
+/// How the servers to deliver to were found for a recipient domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MxSource {
+    /// From the domain's MX records
+    Mx,
+    /// The domain has no MX records, but has A/AAAA records, so the domain itself
+    /// is used as an implicit MX (RFC 5321 section 5.1)
+    ImplicitAddress,
+    /// Neither MX nor A/AAAA records were found.  The domain itself is tried anyway.
+    /// (If the lookups failed, the recipient is deferred without an MX source.)
+    Unresolved,
+    /// From `RemoteDeliveryConfig::mx_overrides`, without looking anything up
+    Override,
+}
+
+/// Why delivery to a recipient failed, for acting on failures without picking apart
+/// the message in `DeliveryResult::Failed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureReason {
+    /// Neither MX nor A/AAAA records were found for the recipient's domain, and it
+    /// could not be reached itself
+    NoMxRecords,
+    /// MX records were found, but none of them are usable
+    NoValidMxRecords,
+    /// None of the servers tried (the MX hosts, or the relay) could be connected to
+    Unreachable,
+    /// The server refused delivery, or the session with it failed in a way that
+    /// retrying will not fix
+    Rejected,
+    /// The recipient was still deferred after their last attempt
+    TooManyAttempts,
+    /// The retry policy gave up on the recipient
+    GaveUp,
+    /// The recipient is on the suppression list
+    Suppressed,
+    /// The recipient's domain is not in `Config::allowed_domains`, or is in
+    /// `Config::denied_domains`
+    DomainNotPermitted,
+}
 
 /// Per-Recipient Delivery Information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,11 +58,37 @@ pub struct InternalRecipientStatus {
     /// lookups take time).
     pub mx_servers: Option<Vec<String>>,
 
+    /// Where `mx_servers` came from. If this is None, they have not been
+    /// determined yet (or were stored by an older version).
+    #[serde(default)]
+    pub mx_source: Option<MxSource>,
+
+    /// When the DNS records `mx_servers` came from expire (their TTL runs out).
+    /// A pass after this looks them up again.  None if they do not expire.
+    #[serde(default)]
+    pub mx_expires_at: Option<SystemTime>,
+
     /// The index into the MX server we are currently trying next
     pub current_mx: usize,
 
+    /// How many worker passes have tried to deliver to this recipient.  Each
+    /// recipient is tried at most `Config::max_attempts_per_recipient` times,
+    /// however many attempts the others take.
+    #[serde(default)]
+    pub attempts: u8,
+
     /// The delivery result (so far) for this recipient
     pub result: DeliveryResult,
+
+    /// Why delivery failed, once `result` is `DeliveryResult::Failed`
+    #[serde(default)]
+    pub failure_reason: Option<FailureReason>,
+
+    /// The server that accepted the email, once `result` is
+    /// `DeliveryResult::Delivered`: the MX host, or the relay, LMTP server or
+    /// sendmail binary
+    #[serde(default)]
+    pub delivered_via: Option<String>,
 }
 
 impl InternalRecipientStatus {
@@ -29,13 +96,36 @@ impl InternalRecipientStatus {
         RecipientStatus {
             recipient: self.email_addr.clone(),
             result: self.result.clone(),
+            failure_reason: self.failure_reason,
+            delivered_via: self.delivered_via.clone(),
+        }
+    }
+
+    /// Fail this recipient
+    pub(crate) fn fail(&mut self, reason: FailureReason, msg: String) {
+        self.result = DeliveryResult::Failed(msg);
+        self.failure_reason = Some(reason);
+    }
+
+    /// Why this recipient could not be delivered to when no server could be
+    /// connected to
+    pub(crate) fn unreachable_reason(&self) -> FailureReason {
+        match self.mx_source {
+            Some(MxSource::Unresolved) => FailureReason::NoMxRecords,
+            _ => FailureReason::Unreachable,
         }
     }
 }
 
 /// Per-Recpiient Delivery Information
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RecipientStatus {
     pub recipient: String,
     pub result: DeliveryResult,
+    /// Why delivery failed, if it did
+    #[serde(default)]
+    pub failure_reason: Option<FailureReason>,
+    /// Which server accepted the email, if one did
+    #[serde(default)]
+    pub delivered_via: Option<String>,
 }
@@ -0,0 +1,84 @@
+use crate::config::Config;
+use crate::delivery_result::DeliveryResult;
+use crate::enhanced_status::EnhancedStatusCode;
+use std::fmt;
+use std::time::Duration;
+
+/// Decides when to retry a deferred recipient.
+///
+/// Set one in `Config::retry_policy`.  `attempt` is how many worker passes have
+/// deferred the recipient so far (1 after the first), and `result` is the deferral.
+/// Returning None gives up on the recipient now, failing them.  Recipients are
+/// failed after `Config::max_attempts_per_recipient` regardless.
+///
+/// An email with several deferred recipients is retried after the shortest of
+/// their delays.
+///
+/// This is implemented for closures, so `Arc::new(|attempt, result| ...)` will do.
+pub trait RetryPolicy: Send + Sync {
+    fn next_delay(&self, attempt: u8, result: &DeliveryResult) -> Option<Duration>;
+
+    /// Like `next_delay`, but with more to go on (such as the server that deferred
+    /// the recipient).  This is the one Mailstrom calls; by default it calls
+    /// `next_delay`.
+    fn next_delay_with_context(&self, context: &RetryContext) -> Option<Duration> {
+        self.next_delay(context.attempt, context.result)
+    }
+}
+
+/// What a `RetryPolicy` is told about a deferred recipient
+#[derive(Debug, Clone, Copy)]
+pub struct RetryContext<'a> {
+    /// How many worker passes have deferred the recipient so far
+    pub attempt: u8,
+    /// The deferral
+    pub result: &'a DeliveryResult,
+    /// The RFC 3463 enhanced status code of the deferral, if the server gave one
+    /// (`4.4.x` for network trouble, `4.2.x` for a full mailbox, and so on)
+    pub status_code: Option<EnhancedStatusCode>,
+    /// The recipient's email address
+    pub recipient: &'a str,
+    /// The server the recipient was last tried at this pass: an MX host, or the
+    /// relay, LMTP server or sendmail binary.  None if they were not tried (for
+    /// instance if their MX records could not be looked up).
+    pub server: Option<&'a str>,
+}
+
+impl<F: Fn(u8, &DeliveryResult) -> Option<Duration> + Send + Sync> RetryPolicy for F {
+    fn next_delay(&self, attempt: u8, result: &DeliveryResult) -> Option<Duration> {
+        self(attempt, result)
+    }
+}
+
+impl fmt::Debug for dyn RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RetryPolicy")
+    }
+}
+
+/// The default retry policy: wait `base` times `factor` to the power of the attempt,
+/// and never give up early.
+#[derive(Clone, Copy, Debug)]
+pub struct ExponentialBackoff {
+    pub base: Duration,
+    pub factor: u32,
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: u8, _result: &DeliveryResult) -> Option<Duration> {
+        let multiplier = self.factor.saturating_pow(u32::from(attempt));
+        Some(self.base.checked_mul(multiplier).unwrap_or(Duration::MAX))
+    }
+}
+
+// Ask the configured retry policy when to retry, falling back to exponential backoff
+// from `base_resend_delay_secs`
+pub fn next_delay(config: &Config, context: &RetryContext) -> Option<Duration> {
+    match config.retry_policy {
+        Some(ref policy) => policy.next_delay_with_context(context),
+        None => ExponentialBackoff {
+            base: Duration::from_secs(config.base_resend_delay_secs),
+            factor: 3,
+        }.next_delay_with_context(context),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::delivery_result::DeliveryResult;
 use crate::message_status::InternalMessageStatus;
 use crate::prepared_email::PreparedEmail;
 use crate::storage::{MailstromStorage, MailstromStorageError};
@@ -22,14 +23,56 @@ pub struct Record {
     email: PreparedEmail,
     status: InternalMessageStatus,
     retrieved: bool,
+    last_used: u64,
 }
 
 #[derive(Default)]
-pub struct MemoryStorage(HashMap<String, Record>);
+pub struct MemoryStorage {
+    records: HashMap<String, Record>,
+    capacity: Option<usize>,
+    clock: u64,
+}
 
 impl MemoryStorage {
     pub fn new() -> MemoryStorage {
-        MemoryStorage(HashMap::new())
+        Default::default()
+    }
+
+    /// Create a MemoryStorage that holds at most `max` emails, evicting the least
+    /// recently stored or updated completed emails (those already reported by
+    /// `retrieve_all_recent` first) to make room.  Emails still being delivered are
+    /// never evicted, so it can grow beyond `max` while they are in flight.
+    pub fn with_capacity(max: usize) -> MemoryStorage {
+        MemoryStorage {
+            capacity: Some(max),
+            ..Default::default()
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    // Evict completed records until we are within capacity
+    fn evict(&mut self) {
+        let max = match self.capacity {
+            Some(max) => max,
+            None => return,
+        };
+        while self.records.len() > max {
+            let victim = self.records
+                .iter()
+                .filter(|&(_, record)| record.status.attempts_remaining == 0)
+                .min_by_key(|&(_, record)| (!record.retrieved, record.last_used))
+                .map(|(message_id, _)| message_id.clone());
+            match victim {
+                Some(message_id) => {
+                    let _ = self.records.remove(&message_id);
+                }
+                None => return,
+            }
+        }
     }
 }
 
@@ -41,14 +84,17 @@ impl MailstromStorage for MemoryStorage {
         email: PreparedEmail,
         internal_message_status: InternalMessageStatus,
     ) -> Result<(), MemoryStorageError> {
-        self.0.insert(
+        let last_used = self.tick();
+        self.records.insert(
             internal_message_status.message_id.clone(),
             Record {
                 email,
                 status: internal_message_status,
                 retrieved: false,
+                last_used,
             },
         );
+        self.evict();
         Ok(())
     }
 
@@ -56,12 +102,15 @@ impl MailstromStorage for MemoryStorage {
         &mut self,
         internal_message_status: InternalMessageStatus,
     ) -> Result<(), MemoryStorageError> {
-        let record: &mut Record = match self.0.get_mut(&internal_message_status.message_id) {
+        let last_used = self.tick();
+        let record: &mut Record = match self.records.get_mut(&internal_message_status.message_id) {
             None => return Err(MemoryStorageError::NotFound),
             Some(record) => record,
         };
 
         record.status = internal_message_status;
+        record.last_used = last_used;
+        self.evict();
         Ok(())
     }
 
@@ -69,7 +118,7 @@ impl MailstromStorage for MemoryStorage {
         &self,
         message_id: &str,
     ) -> Result<(PreparedEmail, InternalMessageStatus), MemoryStorageError> {
-        let record: &Record = match self.0.get(message_id) {
+        let record: &Record = match self.records.get(message_id) {
             None => return Err(MemoryStorageError::NotFound),
             Some(record) => record,
         };
@@ -80,7 +129,7 @@ impl MailstromStorage for MemoryStorage {
         &self,
         message_id: &str,
     ) -> Result<InternalMessageStatus, MemoryStorageError> {
-        let record: &Record = match self.0.get(message_id) {
+        let record: &Record = match self.records.get(message_id) {
             None => return Err(MemoryStorageError::NotFound),
             Some(record) => record,
         };
@@ -88,7 +137,7 @@ impl MailstromStorage for MemoryStorage {
     }
 
     fn retrieve_all_incomplete(&self) -> Result<Vec<InternalMessageStatus>, Self::Error> {
-        Ok(self.0
+        Ok(self.records
             .values()
             .filter_map(|record| {
                 if record.status.attempts_remaining == 0 {
@@ -100,8 +149,15 @@ impl MailstromStorage for MemoryStorage {
             .collect())
     }
 
+    fn count_incomplete(&self) -> Result<usize, Self::Error> {
+        Ok(self.records
+            .values()
+            .filter(|record| record.status.attempts_remaining > 0)
+            .count())
+    }
+
     fn retrieve_all_recent(&mut self) -> Result<Vec<InternalMessageStatus>, Self::Error> {
-        Ok(self.0
+        Ok(self.records
             .values_mut()
             .filter_map(|record| {
                 if record.status.attempts_remaining == 0 {
@@ -117,4 +173,44 @@ impl MailstromStorage for MemoryStorage {
             })
             .collect())
     }
+
+    fn retrieve_failed(&self) -> Result<Vec<InternalMessageStatus>, Self::Error> {
+        Ok(self.records
+            .values()
+            .filter(|record| {
+                record.status.recipients
+                    .iter()
+                    .any(|r| matches!(r.result, DeliveryResult::Failed(_)))
+            })
+            .map(|record| record.status.clone())
+            .collect())
+    }
+
+    fn retrieve_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<InternalMessageStatus>, Self::Error> {
+        let mut statuses: Vec<&InternalMessageStatus> = self.records
+            .values()
+            .map(|record| &record.status)
+            .collect();
+        statuses.sort_by(|a, b| {
+            (a.submitted_at, &a.message_id).cmp(&(b.submitted_at, &b.message_id))
+        });
+        Ok(statuses.into_iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn lookup_idempotency_key(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        Ok(self.records
+            .values()
+            .map(|record| &record.status)
+            .filter(|status| status.options.idempotency_key.as_deref() == Some(key))
+            .max_by_key(|status| status.submitted_at)
+            .map(|status| status.message_id.clone()))
+    }
 }
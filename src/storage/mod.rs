@@ -1,8 +1,10 @@
 pub mod memory_storage;
 pub use self::memory_storage::MemoryStorage;
 
+use crate::delivery_result::DeliveryResult;
 pub use crate::message_status::InternalMessageStatus;
 pub use crate::prepared_email::PreparedEmail;
+pub use crate::recipient_status::{InternalRecipientStatus, MxSource};
 
 pub trait MailstromStorageError: ::std::error::Error {}
 
@@ -37,11 +39,60 @@ pub trait MailstromStorage: Send + Sync {
     /// after shutdown and later startup.
     fn retrieve_all_incomplete(&self) -> Result<Vec<InternalMessageStatus>, Self::Error>;
 
+    /// Count the incomplete emails (those `retrieve_all_incomplete` would return).
+    /// This should be cheap (such as a `COUNT(*)` query), as it is checked whenever
+    /// an email is sent if `Config::max_queue_depth` is set, and by
+    /// `Mailstrom::queue_depth`.  The default counts what `retrieve_all_incomplete`
+    /// returns; override it if that is expensive.
+    fn count_incomplete(&self) -> Result<usize, Self::Error> {
+        Ok(self.retrieve_all_incomplete()?.len())
+    }
+
     /// Retrieve all incomplete emails as well as all complete emails that have become
     /// complete since the last time this function was called. This can be implemented
     /// by storing a retrieved boolean as falswe when update_status saves as complete,
     /// and setting that boolean to true when this function is run.
     fn retrieve_all_recent(&mut self) -> Result<Vec<InternalMessageStatus>, Self::Error>;
+
+    /// Retrieve all emails where delivery to any recipient has permanently failed
+    /// (status only), whether or not they have been retrieved before.  The default
+    /// only finds them among the incomplete emails, missing those that are complete;
+    /// override it to return those too.
+    fn retrieve_failed(&self) -> Result<Vec<InternalMessageStatus>, Self::Error> {
+        Ok(self.retrieve_all_incomplete()?
+            .into_iter()
+            .filter(|status| {
+                status.recipients
+                    .iter()
+                    .any(|r| matches!(r.result, DeliveryResult::Failed(_)))
+            })
+            .collect())
+    }
+
+    /// Retrieve up to `limit` emails (status only), skipping the first `offset`, out
+    /// of all the stored emails ordered by `submitted_at` (oldest first, ties broken
+    /// by message_id).  This must not affect what `retrieve_all_recent` returns.  The
+    /// default only pages through the incomplete emails; override it to include the
+    /// complete ones too.
+    fn retrieve_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<InternalMessageStatus>, Self::Error> {
+        let mut statuses = self.retrieve_all_incomplete()?;
+        statuses.sort_by(|a, b| {
+            (a.submitted_at, &a.message_id).cmp(&(b.submitted_at, &b.message_id))
+        });
+        Ok(statuses.into_iter().skip(offset).take(limit).collect())
+    }
+
+    /// Find the message-id of the most recently submitted email stored with this
+    /// `SendOptions::idempotency_key` (in `InternalMessageStatus::options`), if any.
+    /// The default finds none, so idempotency keys do nothing with storages that
+    /// do not override it.
+    fn lookup_idempotency_key(&self, _key: &str) -> Result<Option<String>, Self::Error> {
+        Ok(None)
+    }
 }
 
 impl MailstromStorageError for lettre::error::Error { }
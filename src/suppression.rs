@@ -0,0 +1,55 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// A shared list of recipient addresses that must not be sent to.
+///
+/// This is a cheap-to-clone handle; all clones refer to the same list. Put one in
+/// `Config::suppression_list` and keep a clone for yourself to inspect or edit it while
+/// Mailstrom is running. The worker skips suppressed recipients, and adds recipients
+/// whose mailbox was reported as unknown (enhanced status `5.1.0` or `5.1.1`).
+///
+/// Addresses are compared case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct SuppressionList(Arc<RwLock<HashSet<String>>>);
+
+impl SuppressionList {
+    pub fn new() -> SuppressionList {
+        SuppressionList(Arc::new(RwLock::new(HashSet::new())))
+    }
+
+    /// Add an address. Returns true if it was not already present.
+    pub fn insert(&self, address: &str) -> bool {
+        match self.0.write() {
+            Ok(mut guard) => guard.insert(normalize(address)),
+            Err(_) => false,
+        }
+    }
+
+    /// Remove an address. Returns true if it was present.
+    pub fn remove(&self, address: &str) -> bool {
+        match self.0.write() {
+            Ok(mut guard) => guard.remove(&normalize(address)),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the address is suppressed
+    pub fn contains(&self, address: &str) -> bool {
+        match self.0.read() {
+            Ok(guard) => guard.contains(&normalize(address)),
+            Err(_) => false,
+        }
+    }
+
+    /// All suppressed addresses (in no particular order)
+    pub fn addresses(&self) -> Vec<String> {
+        match self.0.read() {
+            Ok(guard) => guard.iter().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+fn normalize(address: &str) -> String {
+    address.trim().to_lowercase()
+}
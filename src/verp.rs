@@ -0,0 +1,33 @@
+use crate::config::VerpConfig;
+
+// The longest local part allowed (RFC 5321 section 4.5.3.1.1)
+const MAX_LOCAL_PART: usize = 64;
+
+// The envelope sender for one recipient of an email, given by their index into the
+// email's recipients: `local_part+message-id-index@domain`, with the `@` and any `=`
+// of the message-id escaped as `=40` and `=3D` (every other character a message-id
+// may contain is allowed in a local part).  None if that would make the local part
+// too long.
+pub fn verp_address(verp: &VerpConfig, message_id: &str, recipient: usize) -> Option<String> {
+    let escaped = message_id.replace('=', "=3D").replace('@', "=40");
+    let local_part = format!("{}+{}-{}", verp.local_part, escaped, recipient);
+    if local_part.len() > MAX_LOCAL_PART {
+        return None;
+    }
+    Some(format!("{}@{}", local_part, verp.domain))
+}
+
+// Recover the message-id and recipient index from the address a bounce was sent
+// to, if it is one of our VERP addresses
+pub fn decode_verp_address(verp: &VerpConfig, address: &str) -> Option<(String, usize)> {
+    let address = address.trim().trim_start_matches('<').trim_end_matches('>');
+    let at = address.rfind('@')?;
+    if !address[at + 1..].eq_ignore_ascii_case(&verp.domain) {
+        return None;
+    }
+    let token = address[..at].strip_prefix(&*verp.local_part)?.strip_prefix('+')?;
+    let dash = token.rfind('-')?;
+    let recipient = token[dash + 1..].parse().ok()?;
+    let message_id = token[..dash].replace("=40", "@").replace("=3D", "=");
+    Some((message_id, recipient))
+}
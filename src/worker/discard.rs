@@ -0,0 +1,34 @@
+use crate::config::SimulatedOutcome;
+use crate::delivery_result::{DeliveredResponse, DeliveryResult};
+use crate::prepared_email::PreparedEmail;
+
+// "Deliver" an email without sending it anywhere, giving each recipient the
+// simulated outcome
+pub fn discard_delivery(
+    prepared_email: &PreparedEmail,
+    simulate: &SimulatedOutcome,
+) -> Vec<DeliveryResult> {
+    prepared_email.to.iter().map(|recipient| {
+        let result = match *simulate {
+            SimulatedOutcome::Delivered => None,
+            SimulatedOutcome::Deferred => Some(DeliveryResult::Deferred(
+                1, "451 4.3.0 Simulated deferral".to_owned())),
+            SimulatedOutcome::Failed => Some(DeliveryResult::Failed(
+                "550 5.0.0 Simulated failure".to_owned())),
+            SimulatedOutcome::Scripted(ref script) => script.get(recipient).cloned(),
+        };
+        match result {
+            None | Some(DeliveryResult::Queued) => DeliveryResult::Delivered(DeliveredResponse {
+                code: 250,
+                message: "2.0.0 Discarded".to_owned(),
+                mx_host: "discard".to_owned(),
+                encrypted: false,
+                used_helo: false,
+                dry_run: false,
+            }),
+            // The count is fixed up by the caller
+            Some(DeliveryResult::Deferred(_, message)) => DeliveryResult::Deferred(1, message),
+            Some(result) => result,
+        }
+    }).collect()
+}
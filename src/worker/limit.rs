@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+// Caps how many connections may be open at once, across every delivery that
+// shares it, both in all and to the recipients of any one domain.  A limit of 0
+// means there is no cap.
+pub struct ConnectionLimit {
+    max: usize,
+    max_per_domain: usize,
+    open: Mutex<Open>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    per_domain: HashMap<String, usize>,
+}
+
+// Permission to hold one connection open (for recipients of these domains), given
+// back when dropped
+pub struct ConnectionPermit<'a>(&'a ConnectionLimit, Vec<String>);
+
+impl ConnectionLimit {
+    pub fn new(max: usize, max_per_domain: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max,
+            max_per_domain,
+            open: Mutex::new(Open::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    // Wait (however long it takes) until another connection may be opened, to
+    // deliver to recipients of these domains
+    pub fn acquire(&self, domains: &[String]) -> ConnectionPermit<'_> {
+        let mut domains: Vec<String> = if self.max_per_domain > 0 {
+            domains.to_vec()
+        } else {
+            vec![]
+        };
+        domains.sort();
+        domains.dedup();
+
+        if self.max > 0 || !domains.is_empty() {
+            let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+            while !self.may_open(&open, &domains) {
+                open = self.released.wait(open).unwrap_or_else(|e| e.into_inner());
+            }
+            open.total += 1;
+            for domain in &domains {
+                *open.per_domain.entry(domain.clone()).or_insert(0) += 1;
+            }
+        }
+        ConnectionPermit(self, domains)
+    }
+
+    fn may_open(&self, open: &Open, domains: &[String]) -> bool {
+        if self.max > 0 && open.total >= self.max {
+            return false;
+        }
+        domains.iter().all(|domain| {
+            open.per_domain.get(domain).cloned().unwrap_or(0) < self.max_per_domain
+        })
+    }
+}
+
+impl<'a> Drop for ConnectionPermit<'a> {
+    fn drop(&mut self) {
+        let limit = self.0;
+        if limit.max > 0 || !self.1.is_empty() {
+            let mut open = limit.open.lock().unwrap_or_else(|e| e.into_inner());
+            open.total -= 1;
+            for domain in &self.1 {
+                let remove = match open.per_domain.get_mut(domain) {
+                    Some(count) => {
+                        *count -= 1;
+                        *count == 0
+                    },
+                    None => false,
+                };
+                if remove {
+                    open.per_domain.remove(domain);
+                }
+            }
+            // Those waiting may be waiting on different domains
+            limit.released.notify_all();
+        }
+    }
+}
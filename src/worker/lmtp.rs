@@ -0,0 +1,184 @@
+use crate::config::{Config, LmtpConfig};
+use crate::delivery_result::DeliveryResult;
+use crate::prepared_email::PreparedEmail;
+use crate::worker::smtp::{classify_result, connect_tcp, note_mx_host, pick_sockaddr, read_reply};
+use lettre::smtp::client::net::{Connector, NetworkStream, Timeout};
+use lettre::smtp::client::InnerClient;
+use lettre::smtp::commands::{DataCommand, MailCommand, RcptCommand};
+use lettre::smtp::error::Error as LettreSmtpError;
+use lettre::smtp::extension::{Extension, MailBodyParameter, MailParameter, ServerInfo};
+use lettre::SendableEmail;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+// There is no standard LMTP port, but 24 is the customary one
+const DEFAULT_LMTP_PORT: u16 = 24;
+
+// Deliver an email over LMTP (RFC 2033).  Returns one result per recipient in
+// `prepared_email.to`, in the same order.
+pub fn lmtp_delivery(
+    prepared_email: &PreparedEmail,
+    lmtp_config: &LmtpConfig,
+    config: &Config,
+) -> Vec<DeliveryResult> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "lmtp_delivery",
+        message_id = %prepared_email.message_id,
+        recipients = %prepared_email.to.join(", "),
+        server = %lmtp_config.domain_name,
+    ).entered();
+
+    let all = |result: DeliveryResult| vec![result; prepared_email.to.len()];
+
+    // lettre::EmailAddress checks validity.  But we checked that when we created
+    // PreparedEmail so this conversion should always pass.
+    let sendable_email = match prepared_email.as_sendable_email() {
+        Ok(se) => se,
+        Err(e) => {
+            warn!("Invalid email address error: {:?}", e);
+            return all(DeliveryResult::Failed(format!("Invalid email address error: {:?}", e)));
+        }
+    };
+
+    let timeout = Duration::from_secs(config.smtp_timeout_secs);
+
+    if let Some(ref path) = lmtp_config.unix_socket {
+        debug!("Starting LMTP delivery to [{}] at {}",
+               prepared_email.to.join(", "), path.display());
+
+        #[cfg(unix)]
+        {
+            let stream = match crate::worker::unix::UnixSocketStream::connect(path) {
+                Ok(stream) => stream,
+                Err(e) => return all(classify_result(Err(LettreSmtpError::Io(e)))),
+            };
+            let mut client = InnerClient::new();
+            client.set_stream(stream);
+            let results = lmtp_session(&mut client, sendable_email, config, timeout);
+            client.close();
+            let mx_host = path.display().to_string();
+            return results.into_iter().map(|r| note_mx_host(r, &mx_host)).collect();
+        }
+
+        #[cfg(not(unix))]
+        return all(DeliveryResult::Failed(
+            "Unix sockets are not supported on this platform".to_owned()));
+    }
+
+    let port = lmtp_config.port.unwrap_or(DEFAULT_LMTP_PORT);
+    debug!("Starting LMTP delivery to [{}] at {}:{}",
+           prepared_email.to.join(", "), lmtp_config.domain_name, port);
+
+    let sockaddr = match (&*lmtp_config.domain_name, port).to_socket_addrs() {
+        Ok(iter) => pick_sockaddr(iter, None),
+        Err(e) => {
+            warn!("ToSocketAddr failed for ({}, {}): {:?}", lmtp_config.domain_name, port, e);
+            None
+        }
+    };
+    let sockaddr = match sockaddr {
+        Some(sa) => sa,
+        None => return all(DeliveryResult::Failed(format!(
+            "No SockAddrs for ({}, {})", lmtp_config.domain_name, port))),
+    };
+    let stream = match connect_tcp(&sockaddr, None, timeout) {
+        Ok(stream) => stream,
+        Err(e) => return all(classify_result(Err(LettreSmtpError::Io(e)))),
+    };
+
+    let mut client: InnerClient = InnerClient::new();
+    client.set_stream(NetworkStream::Tcp(stream));
+    let results = lmtp_session(&mut client, sendable_email, config, timeout);
+    client.close();
+    results.into_iter().map(|r| note_mx_host(r, &lmtp_config.domain_name)).collect()
+}
+
+// Run the LMTP conversation, giving each recipient their own result
+fn lmtp_session<S>(
+    client: &mut InnerClient<S>,
+    sendable_email: SendableEmail,
+    config: &Config,
+    timeout: Duration,
+) -> Vec<DeliveryResult>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    let mut results: Vec<Option<DeliveryResult>> = vec![None; sendable_email.envelope().to().len()];
+
+    if let Err(e) = lmtp_transaction(client, sendable_email, config, timeout, &mut results) {
+        // Everyone who did not get a reply of their own gets the session error
+        let result = classify_result(Err(e));
+        for r in results.iter_mut().filter(|r| r.is_none()) {
+            *r = Some(result.clone());
+        }
+    }
+
+    // Every entry is filled in by now
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+fn lmtp_transaction<S>(
+    client: &mut InnerClient<S>,
+    sendable_email: SendableEmail,
+    config: &Config,
+    timeout: Duration,
+    results: &mut [Option<DeliveryResult>],
+) -> Result<(), LettreSmtpError>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    client.set_timeout(Some(timeout))?;
+
+    read_reply(client)?;
+
+    let lhlo_response = client.command(format!("LHLO {}\r\n", config.helo_name))?;
+    let server_info = ServerInfo::from_response(&lhlo_response)?;
+    debug!("(worker) server {}", server_info);
+
+    let mut mail_parameters = vec![];
+    if server_info.supports_feature(Extension::EightBitMime) {
+        mail_parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    if server_info.supports_feature(Extension::SmtpUtfEight) {
+        mail_parameters.push(MailParameter::SmtpUtfEight);
+    }
+
+    let envelope = sendable_email.envelope().clone();
+    client.command(MailCommand::new(envelope.from().cloned(), mail_parameters))?;
+
+    // A rejected recipient does not stop delivery to the others
+    let mut accepted: Vec<usize> = Vec::new();
+    for (i, to_address) in envelope.to().iter().enumerate() {
+        match client.command(RcptCommand::new(to_address.clone(), vec![])) {
+            Ok(_) => accepted.push(i),
+            Err(e @ LettreSmtpError::Transient(_)) | Err(e @ LettreSmtpError::Permanent(_)) => {
+                results[i] = Some(classify_result(Err(e)));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if accepted.is_empty() {
+        return Ok(());
+    }
+
+    client.command(DataCommand)?;
+
+    // The server replies once for each accepted recipient, in RCPT order
+    // (RFC 2033 section 4.2)
+    let mut first = Some(client.message(Box::new(sendable_email.message())));
+    for i in accepted {
+        let response = match first.take() {
+            Some(response) => response,
+            None => read_reply(client),
+        };
+        match response {
+            Ok(_) | Err(LettreSmtpError::Transient(_)) | Err(LettreSmtpError::Permanent(_)) => {
+                results[i] = Some(classify_result(response));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
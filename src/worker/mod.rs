@@ -1,20 +1,32 @@
-mod mx;
+pub mod limit;
+mod discard;
+mod lmtp;
+pub mod mx;
+mod proxy;
+mod sendmail;
 mod smtp;
-mod task;
+pub mod task;
+#[cfg(unix)]
+mod unix;
 
 use std::collections::BTreeSet;
+use std::io::Error as IoError;
+use std::net::IpAddr;
 use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use trust_dns_resolver::Resolver;
-use trust_dns_resolver::config::{ResolverConfig, NameServerConfig};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts, NameServerConfig};
 
-use self::task::{Task, TaskType};
-use crate::config::{Config, DeliveryConfig, ResolverSetup};
+use self::limit::ConnectionLimit;
+use self::task::{add_task, Task, TaskType};
+use crate::config::{Config, DeliveryConfig, RemoteDeliveryConfig, ResolverSetup};
 use crate::delivery_result::DeliveryResult;
 use crate::message_status::InternalMessageStatus;
 use crate::prepared_email::PreparedEmail;
+use crate::recipient_status::FailureReason;
+use crate::retry::RetryContext;
 use crate::storage::MailstromStorage;
 
 const LOOP_DELAY: u64 = 10;
@@ -62,6 +74,9 @@ pub struct Worker<S: MailstromStorage + 'static> {
 
     worker_status: Arc<RwLock<u8>>,
 
+    // When we last delivered to a recipient
+    last_delivery: Arc<RwLock<Option<SystemTime>>>,
+
     config: Config,
 
     // Persistent shared storage
@@ -73,6 +88,13 @@ pub struct Worker<S: MailstromStorage + 'static> {
     paused: bool,
 
     last_refresh: Instant,
+
+    // Position in config.source_addr_rotation
+    source_addr_index: usize,
+
+    // Caps simultaneous SMTP connections (config.max_concurrent_connections and
+    // config.max_connections_per_domain)
+    connection_limit: Arc<ConnectionLimit>,
 }
 
 impl<S: MailstromStorage + 'static> Worker<S> {
@@ -80,18 +102,32 @@ impl<S: MailstromStorage + 'static> Worker<S> {
         receiver: mpsc::Receiver<Message>,
         storage: Arc<RwLock<S>>,
         worker_status: Arc<RwLock<u8>>,
+        last_delivery: Arc<RwLock<Option<SystemTime>>>,
         config: Config,
     ) -> Worker<S> {
         let mut worker = Worker {
             receiver,
             worker_status,
-            config,
+            last_delivery,
             storage,
             tasks: BTreeSet::new(),
             paused: true,
             last_refresh: Instant::now(),
+            source_addr_index: 0,
+            connection_limit: Arc::new(ConnectionLimit::new(config.max_concurrent_connections,
+                                                             config.max_connections_per_domain)),
+            config,
         };
 
+        if worker.config.danger_accept_invalid_certs {
+            warn!("(worker) TLS certificate verification is DISABLED. \
+                   Do not use this configuration in production.");
+        }
+        if worker.config.danger_accept_invalid_hostnames {
+            warn!("(worker) TLS hostname verification is DISABLED. \
+                   Do not use this configuration in production.");
+        }
+
         // Load the incomplete (queued and/or deferred) email statuses, for tasking
         worker.refresh_resend_tasks();
 
@@ -118,12 +154,20 @@ impl<S: MailstromStorage + 'static> Worker<S> {
         // Load the incomplete (queued and/or deferred) email statuses, for tasking
         if let Ok(guard) = (*self.storage).write() {
             if let Ok(mut isvec) = (*guard).retrieve_all_incomplete() {
-                // Create one task for each queued/deferred email
+                // Create one task for each queued/deferred email, at the time its
+                // retry was scheduled for (or now, if that has passed)
                 for is in isvec.drain(..) {
-                    self.tasks.insert(Task {
+                    let time = match is.next_retry_at
+                        .and_then(|at| at.duration_since(SystemTime::now()).ok())
+                    {
+                        Some(delay) => Instant::now() + delay,
+                        None => Instant::now(),
+                    };
+                    add_task(&mut self.tasks, Task {
                         tasktype: TaskType::Resend,
-                        time: Instant::now(),
+                        time,
                         message_id: is.message_id.clone(),
+                        priority: is.options.priority,
                     });
                 }
             } else {
@@ -136,29 +180,14 @@ impl<S: MailstromStorage + 'static> Worker<S> {
         self.last_refresh = Instant::now();
     }
 
-    pub fn run(&mut self) {
+    // Run until told to terminate.  Remote delivery uses `resolver` if given, or
+    // creates its own.
+    pub fn run(&mut self, resolver: Option<Resolver>) {
         let resolver: Option<Resolver> = {
-            if let DeliveryConfig::Remote(ref rdc) = self.config.delivery {
-                let result = match rdc.resolver_setup {
-                    ResolverSetup::SystemConf => Resolver::from_system_conf(),
-                    ResolverSetup::Google => Resolver::new(
-                        ResolverConfig::google(), Default::default()),
-                    ResolverSetup::Cloudflare => Resolver::new(
-                        ResolverConfig::cloudflare(), Default::default()),
-                    ResolverSetup::Quad9 => Resolver::new(
-                        ResolverConfig::quad9(), Default::default()),
-                    ResolverSetup::Specific {
-                        socket, protocol, ref tls_dns_name
-                    } => Resolver::new(
-                        ResolverConfig::from_parts(
-                            None, vec![], vec![NameServerConfig {
-                                socket_addr: socket,
-                                protocol: protocol,
-                                tls_dns_name: tls_dns_name.clone()
-                            }]),
-                        Default::default()),
-                };
-                match result {
+            if resolver.is_some() {
+                resolver
+            } else if let DeliveryConfig::Remote(ref rdc) = self.config.delivery {
+                match create_resolver(rdc) {
                     Ok(r) => Some(r),
                     Err(e) => {
                         *self.worker_status.write().unwrap() =
@@ -210,10 +239,12 @@ impl<S: MailstromStorage + 'static> Worker<S> {
                         debug!("(worker) received SendEmail command");
                         // Create a task (don't do it right away) so we can more easily
                         // code pause-continue logic and eventually multiple worker threads
-                        self.tasks.insert(Task {
+                        let priority = self.priority_of(&message_id);
+                        add_task(&mut self.tasks, Task {
                             tasktype: TaskType::Resend,
                             time: Instant::now(),
-                            message_id
+                            message_id,
+                            priority,
                         });
                     }
                     Message::Terminate => {
@@ -238,29 +269,35 @@ impl<S: MailstromStorage + 'static> Worker<S> {
                 }
 
 
-                // Copy out all the tasks that are due
+                // Copy out all the tasks that are due, highest priority first (the
+                // sort is stable, so otherwise in time order)
                 let now = Instant::now();
-                let due_tasks: Vec<Task> = self.tasks
+                let mut due_tasks: Vec<Task> = self.tasks
                     .iter()
                     .filter(|t| now > t.time)
                     .cloned()
                     .collect();
+                due_tasks.sort_by_key(|t| ::std::cmp::Reverse(t.priority));
 
-                // Handle all these due tasks
+                // Handle all these due tasks.  Each is removed first, so that the
+                // retry it schedules is not taken for a duplicate of it.
                 for task in &due_tasks {
+                    self.tasks.remove(task);
                     let worker_status = self.handle_task(task, resolver.as_ref());
                     if worker_status != WorkerStatus::Ok {
                         *self.worker_status.write().unwrap() = worker_status as u8;
                         debug!("(worker) failed and terminated");
                         return;
                     }
-                    self.tasks.remove(task);
                 }
             }
         }
     }
 
     fn handle_task(&mut self, task: &Task, resolver: Option<&Resolver>) -> WorkerStatus {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("handle_task", message_id = %task.message_id).entered();
+
         match task.tasktype {
             TaskType::Resend => {
                 debug!("(worker) resending a (queued/deferred) email");
@@ -288,13 +325,42 @@ impl<S: MailstromStorage + 'static> Worker<S> {
         mut internal_message_status: InternalMessageStatus,
         resolver: Option<&Resolver>,
     ) -> WorkerStatus {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "send_email",
+            message_id = %internal_message_status.message_id,
+            attempt = internal_message_status.recipients.iter()
+                .filter(|r| !r.result.completed())
+                .map(|r| r.attempts)
+                .max()
+                .unwrap_or(0) + 1,
+        ).entered();
 
         debug!("(worker) Attempting to send message id={} ({} attempts remaining)",
                internal_message_status.message_id,
                internal_message_status.attempts_remaining);
 
+        // Apply any per-email overrides
+        let config = internal_message_status.options.apply(&self.config);
+
         // Determine MX records only if doing remote delivery
-        if let DeliveryConfig::Remote(_) = self.config.delivery {
+        if let DeliveryConfig::Remote(ref rdc) = config.delivery {
+
+            // Look up again the servers of recipients whose records have expired
+            let now = SystemTime::now();
+            for recipient in &mut internal_message_status.recipients {
+                let expired = match recipient.mx_expires_at {
+                    Some(expires_at) => expires_at <= now,
+                    None => false,
+                };
+                if expired && !recipient.result.completed() {
+                    debug!("(worker) MX records for {} have expired", recipient.domain);
+                    recipient.mx_servers = None;
+                    recipient.mx_source = None;
+                    recipient.mx_expires_at = None;
+                    recipient.current_mx = 0;
+                }
+            }
 
             let mut need_mx: bool = false;
             for recipient in &internal_message_status.recipients {
@@ -304,11 +370,41 @@ impl<S: MailstromStorage + 'static> Worker<S> {
                 }
             }
 
+            // Use our own resolver if this email overrides the delivery configuration
+            let mut resolver_error: Option<String> = None;
+            let own_resolver = match internal_message_status.options.delivery {
+                Some(_) if need_mx => match create_resolver(rdc) {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        error!("(worker) failed to create resolver: {:?}", e);
+                        resolver_error = Some(format!("Could not create resolver: {}", e));
+                        None
+                    }
+                },
+                _ => None,
+            };
+
             if need_mx {
-                crate::worker::mx::get_mx_records_for_email(
-                    &mut internal_message_status,
-                    resolver.unwrap() // Should always succeed
-                );
+                match own_resolver.as_ref().or(resolver) {
+                    Some(resolver) => crate::worker::mx::get_mx_records_for_email(
+                        &mut internal_message_status,
+                        resolver,
+                        rdc
+                    ),
+                    None => {
+                        // Without a resolver the MX records cannot be looked up this
+                        // time, so those waiting for them are deferred (and renumbered
+                        // by the pass, as for a failed lookup)
+                        let msg = resolver_error
+                            .unwrap_or_else(|| "No resolver available".to_owned());
+                        for recipient in &mut internal_message_status.recipients {
+                            if recipient.mx_servers.is_none() && !recipient.result.completed() {
+                                recipient.result =
+                                    DeliveryResult::Deferred(recipient.attempts, msg.clone());
+                            }
+                        }
+                    }
+                }
 
                 // Update storage with this MX information
                 let status = self.update_status(&internal_message_status);
@@ -318,64 +414,112 @@ impl<S: MailstromStorage + 'static> Worker<S> {
             }
         }
 
-        // Fail all recipients after too many worker attempts
-        if internal_message_status.attempts_remaining == 0 {
-            for recipient in &mut internal_message_status.recipients {
-                let mut data: Option<(u8, String)> = None;
-                if let DeliveryResult::Deferred(attempts, ref msg) = recipient.result {
-                    data = Some((attempts, msg.clone()));
-                }
-                if data.is_some() {
-                    let (attempts, msg) = data.unwrap();
-                    recipient.result = DeliveryResult::Failed(format!(
-                        "Too many attempts ({}): {}",
-                        attempts, msg
-                    ));
-                }
+        // Fail recipients who have already had all their attempts (such as if
+        // max_attempts_per_recipient was lowered since)
+        for recipient in &mut internal_message_status.recipients {
+            if recipient.attempts < config.max_attempts_per_recipient {
+                continue;
+            }
+            let mut msg: Option<String> = None;
+            if let DeliveryResult::Deferred(_, ref m) = recipient.result {
+                msg = Some(m.clone());
+            }
+            if let Some(msg) = msg {
+                let failure = format!("Too many attempts ({}): {}", recipient.attempts, msg);
+                recipient.fail(FailureReason::TooManyAttempts, failure);
             }
         }
 
         // Attempt delivery of the email
-        if deliver_to_all_servers(&email, &mut internal_message_status, &self.config) {
+        let count_delivered = |ims: &InternalMessageStatus| ims.recipients
+            .iter()
+            .filter(|r| matches!(r.result, DeliveryResult::Delivered(_)))
+            .count();
+        let delivered_before = count_delivered(&internal_message_status);
+        let source_addr = self.next_source_addr();
+        let retry_delay = deliver_to_all_servers(&email, &mut internal_message_status, &config,
+                                                 source_addr, &self.connection_limit);
+        if count_delivered(&internal_message_status) > delivered_before {
+            if let Ok(mut last_delivery) = self.last_delivery.write() {
+                *last_delivery = Some(SystemTime::now());
+            }
+        }
+        if retry_delay.is_none() {
             internal_message_status.attempts_remaining = 0;
 
             debug!("(worker) message id={} delivered to all recipients.",
                    internal_message_status.message_id);
         } else {
-            internal_message_status.attempts_remaining -= 1;
+            // The recipient with the most attempts left decides how many the email
+            // has left
+            internal_message_status.attempts_remaining = internal_message_status.recipients
+                .iter()
+                .filter(|r| !r.result.completed())
+                .map(|r| config.max_attempts_per_recipient.saturating_sub(r.attempts))
+                .max()
+                .unwrap_or(0);
             debug!("(worker) message id={} not delivered to all recipients ({} attempts remaining)",
                    internal_message_status.message_id,
                    internal_message_status.attempts_remaining);
         }
 
+        let delay = match retry_delay {
+            Some(delay) if internal_message_status.attempts_remaining > 0 => {
+                internal_message_status.next_retry_at = Some(SystemTime::now() + delay);
+                Some(delay)
+            }
+            _ => {
+                internal_message_status.next_retry_at = None;
+                None
+            }
+        };
+
         // Update storage with the new delivery results
         let status = self.update_status(&internal_message_status);
         if status != WorkerStatus::Ok {
             return status;
         }
 
-        if internal_message_status.attempts_remaining > 0 {
-            let attempt = 3 - internal_message_status.attempts_remaining;
-            // exponential backoff
-            let delay = Duration::from_secs(
-                self.config.base_resend_delay_secs * 3u64.pow(u32::from(attempt)),
-            );
+        if let Some(delay) = delay {
             debug!("(worker) Queueing task to retry id={} in {} seconds",
                 &internal_message_status.message_id,
                 delay.as_secs()
             );
 
             // Create a new worker task to retry later
-            self.tasks.insert(Task {
+            add_task(&mut self.tasks, Task {
                 tasktype: TaskType::Resend,
                 time: Instant::now() + delay,
                 message_id: internal_message_status.message_id.clone(),
+                priority: internal_message_status.options.priority,
             });
         }
 
         WorkerStatus::Ok
     }
 
+    // The priority of a stored email (0 if it cannot be read, in which case its task
+    // will fail to read it too)
+    fn priority_of(&self, message_id: &str) -> u8 {
+        match (*self.storage).read() {
+            Ok(guard) => (*guard).retrieve_status(message_id)
+                .map(|status| status.options.priority)
+                .unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    // The local address to send the next message from
+    fn next_source_addr(&mut self) -> Option<IpAddr> {
+        let rotation = &self.config.source_addr_rotation;
+        if rotation.is_empty() {
+            return self.config.source_addr;
+        }
+        let addr = rotation[self.source_addr_index % rotation.len()];
+        self.source_addr_index = self.source_addr_index.wrapping_add(1);
+        Some(addr)
+    }
+
     fn update_status(&mut self, internal_message_status: &InternalMessageStatus) -> WorkerStatus {
         // Lock the storage
         let mut guard = match (*self.storage).write() {
@@ -395,27 +539,145 @@ impl<S: MailstromStorage + 'static> Worker<S> {
     }
 }
 
+// Create a DNS resolver
+pub fn create_resolver(rdc: &RemoteDeliveryConfig) -> Result<Resolver, IoError> {
+    let (config, mut opts) = match rdc.resolver_setup {
+        ResolverSetup::SystemConf => trust_dns_resolver::system_conf::read_system_conf()?,
+        ResolverSetup::Google => (ResolverConfig::google(), ResolverOpts::default()),
+        ResolverSetup::Cloudflare => (ResolverConfig::cloudflare(), ResolverOpts::default()),
+        ResolverSetup::Quad9 => (ResolverConfig::quad9(), ResolverOpts::default()),
+        ResolverSetup::Specific {
+            socket, protocol, ref tls_dns_name
+        } => (
+            ResolverConfig::from_parts(
+                None, vec![], vec![NameServerConfig {
+                    socket_addr: socket,
+                    protocol: protocol,
+                    tls_dns_name: tls_dns_name.clone()
+                }]),
+            ResolverOpts::default()),
+        ResolverSetup::Custom(ref config, ref opts) => (config.clone(), *opts),
+    };
+    if let Some(secs) = rdc.dns_timeout_secs {
+        opts.timeout = Duration::from_secs(secs);
+    }
+    if let Some(attempts) = rdc.dns_attempts {
+        opts.attempts = attempts;
+    }
+    if rdc.validate_dnssec {
+        // The resolver would quietly skip validation if it was built without it
+        if !cfg!(feature = "dnssec") {
+            return Err(IoError::other("validate_dnssec requires the dnssec feature"));
+        }
+        opts.validate = true;
+    }
+    Resolver::new(config, opts)
+}
+
 struct MxDelivery {
     mx_server: String,      // domain name
     mx_port: u16,           // port (defaults to 25)
     recipients: Vec<usize>, // index into InternalMessageStatus.recipients
 }
 
-// Deliver email to all servers.  Returns true if the job is done, false if more work
-// is required later on.
+// Deliver email to all servers.  Returns None if the job is done, or how long to wait
+// before the more work that is required.
 fn deliver_to_all_servers(
     email: &PreparedEmail,
     internal_message_status: &mut InternalMessageStatus,
-    config: &Config
-) -> bool {
+    config: &Config,
+    source_addr: Option<IpAddr>,
+    connection_limit: &ConnectionLimit,
+) -> Option<Duration> {
+    // A pass counts as one attempt for each recipient it tries, however many MX
+    // servers it tries them on.  (Statuses stored by older versions only count the
+    // attempts in the deferral.)
+    for recip in &mut internal_message_status.recipients {
+        if recip.result.completed() {
+            continue;
+        }
+        let attempts = match recip.result {
+            DeliveryResult::Deferred(attempts, _) => recip.attempts.max(attempts),
+            _ => recip.attempts,
+        };
+        recip.attempts = attempts.saturating_add(1);
+    }
+    let pass_attempts: Vec<u8> = internal_message_status.recipients
+        .iter()
+        .map(|r| r.attempts)
+        .collect();
+
+    // Recipients whose domain could not be looked up this pass are not tried, but
+    // their deferral is numbered by this pass just the same
+    if let DeliveryConfig::Remote(_) = config.delivery {
+        for (r, recip) in internal_message_status.recipients.iter_mut().enumerate() {
+            if let (None, DeliveryResult::Deferred(_, ref msg)) = (&recip.mx_servers, &recip.result) {
+                recip.result = DeliveryResult::Deferred(pass_attempts[r], msg.clone());
+            }
+        }
+    }
+
     // Plan delivery to each MX server
     let mx_deliveries = plan_mxdelivery_sessions(internal_message_status, config);
 
-    let mut complete = true;
+    // The server each recipient was last tried at, and whether any server they
+    // were tried at could be connected to
+    let mut last_server: Vec<Option<&str>> = vec![None; pass_attempts.len()];
+    let mut reached: Vec<bool> = vec![false; pass_attempts.len()];
     for mx_delivery in &mx_deliveries {
-        complete &= deliver_to_one_server(email, internal_message_status, config, mx_delivery);
+        let (tried, unreachable) = deliver_to_one_server(
+            email, internal_message_status, config, mx_delivery, source_addr,
+            connection_limit, &pass_attempts);
+        for r in tried {
+            last_server[r] = Some(&mx_delivery.mx_server);
+            reached[r] |= !unreachable;
+        }
+    }
+
+    // Fail recipients who are still deferred after their last attempt, or whom the
+    // retry policy gives up on, and retry the rest after the shortest delay
+    let mut retry_delay: Option<Duration> = None;
+    for (r, recip) in internal_message_status.recipients.iter_mut().enumerate() {
+        let server = last_server[r];
+        let mut failure: Option<(FailureReason, String)> = None;
+        if let DeliveryResult::Deferred(attempts, ref msg) = recip.result {
+            let context = RetryContext {
+                attempt: attempts,
+                result: &recip.result,
+                status_code: recip.result.enhanced_status_code(),
+                recipient: &recip.smtp_email_addr,
+                server,
+            };
+            if attempts >= config.max_attempts_per_recipient {
+                // If nothing could be connected to, that is the real problem
+                let reason = match server {
+                    Some(_) if !reached[r] => recip.unreachable_reason(),
+                    _ => FailureReason::TooManyAttempts,
+                };
+                failure = Some((reason, format!("Too many attempts ({}): {}", attempts, msg)));
+            } else {
+                match crate::retry::next_delay(config, &context) {
+                    Some(delay) => retry_delay = Some(retry_delay.map_or(delay, |d| d.min(delay))),
+                    None => failure = Some((FailureReason::GaveUp,
+                                            format!("Gave up after {} attempts: {}", attempts, msg))),
+                }
+            }
+        }
+        if let Some((reason, failure)) = failure {
+            debug!("(worker) delivery to {} failed: {}", recip.smtp_email_addr, failure);
+            recip.fail(reason, failure);
+            if let Some(ref listener) = config.listener {
+                listener.on_result(&internal_message_status.message_id,
+                                   &recip.smtp_email_addr, &recip.result);
+            }
+        }
+    }
+
+    if internal_message_status.recipients.iter().all(|r| r.result.completed()) {
+        None
+    } else {
+        Some(retry_delay.unwrap_or_default())
     }
-    complete
 }
 
 fn plan_mxdelivery_sessions(
@@ -425,12 +687,45 @@ fn plan_mxdelivery_sessions(
     // If we are using DeliveryConfig::Relay(_), the answer is straightforward
     if let DeliveryConfig::Relay(ref relay_config) = config.delivery {
         return vec![MxDelivery {
-            mx_server: relay_config.domain_name.clone(),
+            mx_server: match relay_config.unix_socket {
+                Some(ref path) => path.display().to_string(),
+                None => relay_config.domain_name.clone(),
+            },
             mx_port: relay_config.port.unwrap_or(25_u16),
             recipients: (0..internal_message_status.recipients.len()).collect()
         }];
     }
 
+    // Likewise for DeliveryConfig::Sendmail { .. }
+    if let DeliveryConfig::Sendmail { ref path, .. } = config.delivery {
+        return vec![MxDelivery {
+            mx_server: path.display().to_string(),
+            mx_port: 0,
+            recipients: (0..internal_message_status.recipients.len()).collect()
+        }];
+    }
+
+    // And for DeliveryConfig::Lmtp(_)
+    if let DeliveryConfig::Lmtp(ref lmtp_config) = config.delivery {
+        return vec![MxDelivery {
+            mx_server: match lmtp_config.unix_socket {
+                Some(ref path) => path.display().to_string(),
+                None => lmtp_config.domain_name.clone(),
+            },
+            mx_port: lmtp_config.port.unwrap_or(24),
+            recipients: (0..internal_message_status.recipients.len()).collect()
+        }];
+    }
+
+    // And for DeliveryConfig::Discard { .. }
+    if let DeliveryConfig::Discard { .. } = config.delivery {
+        return vec![MxDelivery {
+            mx_server: "discard".to_owned(),
+            mx_port: 0,
+            recipients: (0..internal_message_status.recipients.len()).collect()
+        }];
+    }
+
     let mut mx_deliveries: Vec<MxDelivery> = Vec::new();
 
     for r_index in 0..internal_message_status.recipients.len() {
@@ -442,36 +737,28 @@ fn plan_mxdelivery_sessions(
             _ => {}
         }
 
-        // If recipient was deferred too many times, fail them and skip them
-        let mut data: Option<(u8, String)> = None;
-        if let DeliveryResult::Deferred(a, ref msg) = recip.result {
-            data = Some((a, msg.clone()));
-        };
-        if data.is_some() {
-            let (attempts, msg) = data.unwrap();
-            // We allow 5 attempts (even though worker does 3 passes, we might try
-            // across multiple MX servers)
-            if attempts >= 5 {
-                debug!("(worker) delivery failed after 5 attempts.");
-                recip.result = DeliveryResult::Failed(
-                    format!("Failed after 5 attempts: {}", msg));
+        // Skip (and complete) if no MX servers, unless the lookup failed and they
+        // were deferred
+        if recip.mx_servers.is_none() {
+            if let DeliveryResult::Deferred(..) = recip.result {
                 continue;
             }
-        }
-
-        // Skip (and complete) if no MX servers
-        if recip.mx_servers.is_none() {
             debug!("(worker) delivery failed (no valid MX records).");
-            recip.result = DeliveryResult::Failed(
-                "MX records found but none are valid".to_owned());
+            recip.fail(FailureReason::NoValidMxRecords,
+                       "MX records found but none are valid".to_owned());
             continue;
         }
 
-        // Sequence through this recipients MX servers
+        // Sequence through this recipients MX servers, from where the last pass
+        // left off
         let mx_servers: &Vec<String> = recip.mx_servers.as_ref().unwrap();
+        let max_mx = match config.max_mx_per_pass {
+            0 => mx_servers.len(),
+            max => max,
+        };
 
         // Add to our MxDelivery vector
-        for item in mx_servers.iter().skip(recip.current_mx) {
+        for item in mx_servers.iter().skip(recip.current_mx).take(max_mx) {
             // Find the index of the MX server in our mx_deliveries array
             let maybe_position = mx_deliveries.iter().position(|mxd| mxd.mx_server == *item);
             match maybe_position {
@@ -494,83 +781,197 @@ fn plan_mxdelivery_sessions(
     mx_deliveries
 }
 
-// Organize delivery for one-SMTP-delivery per MX server, and then use smtp_deliver()
-// Returns true only if all recipient deliveries have been completed (rather than deferred)
+// Organize delivery for one-SMTP-delivery per MX server, and then use smtp_deliver().
+// `pass_attempts` gives the attempt number of this pass for each recipient.  Returns
+// the recipients that were tried, and whether the server could not be connected to.
 fn deliver_to_one_server(
     email: &PreparedEmail,
     internal_message_status: &mut InternalMessageStatus,
     config: &Config,
-    mx_delivery: &MxDelivery
-) -> bool {
-
-    let mut deferred_some: bool = false;
+    mx_delivery: &MxDelivery,
+    source_addr: Option<IpAddr>,
+    connection_limit: &ConnectionLimit,
+    pass_attempts: &[u8],
+) -> (Vec<usize>, bool) {
+    // Fail any recipients on the suppression list without sending to them
+    if let Some(ref suppression_list) = config.suppression_list {
+        for r in &mx_delivery.recipients {
+            let recip = &mut internal_message_status.recipients[*r];
+            if !recip.result.completed() && suppression_list.contains(&recip.smtp_email_addr) {
+                debug!("(worker) recipient {} is suppressed.", recip.smtp_email_addr);
+                recip.fail(FailureReason::Suppressed,
+                           "Recipient is on the suppression list".to_owned());
+                if let Some(ref listener) = config.listener {
+                    listener.on_result(&internal_message_status.message_id,
+                                       &recip.smtp_email_addr, &recip.result);
+                }
+            }
+        }
+    }
 
     // Per-MX version of the prepared email
     let mut mx_prepared_email = email.clone();
 
-    // Rebuild the 'To:' list; only add recipients for *this* MX server,
-    // and for which delivery has not already completed
-    mx_prepared_email.to = mx_delivery.recipients
+    // Only deliver to recipients for *this* MX server for which delivery has not
+    // already completed
+    let session_recipients: Vec<usize> = mx_delivery.recipients
         .iter()
-        .filter_map(|r| {
-            if internal_message_status.recipients[*r].result.completed() {
-                None
-            } else {
-                Some(
-                    internal_message_status.recipients[*r]
-                        .smtp_email_addr
-                        .clone(),
-                )
-            }
-        })
+        .cloned()
+        .filter(|r| !internal_message_status.recipients[*r].result.completed())
+        .collect();
+
+    // Rebuild the envelope recipients to match.  The message itself is left alone:
+    // its To/Cc headers still list all of the original recipients, just as when a
+    // message is sent to several MX servers (or Bcc'd), since they describe the
+    // message rather than this delivery.  Only the envelope decides who gets it.
+    mx_prepared_email.to = session_recipients
+        .iter()
+        .map(|r| internal_message_status.recipients[*r].smtp_email_addr.clone())
         .collect();
 
     // Skip this MX server if no addresses to deliver to
     // (this can happen if a previous server already handled its recipients and
     // the filter_map above removed them all)
     if mx_prepared_email.to.is_empty() {
-        return true;
+        return (session_recipients, false);
+    }
+
+    if let Some(ref listener) = config.listener {
+        for recipient in &mx_prepared_email.to {
+            listener.on_attempt(&internal_message_status.message_id, recipient,
+                                &mx_delivery.mx_server);
+        }
     }
 
-    // Actually deliver to this SMTP server
+    // Actually deliver to this SMTP server (or sendmail, or LMTP server), getting
+    // a result for each session recipient.  Only sendmail cannot tell them apart.
     // 'attempt' field in results will be set to 1
-    let result = crate::worker::smtp::smtp_delivery(
-        &mx_prepared_email,
-        &*mx_delivery.mx_server,
-        mx_delivery.mx_port,
-        config);
+    let per_recipient_results = config.verp.is_some()
+        || !matches!(config.delivery, DeliveryConfig::Sendmail { .. });
+    let deliver = |prepared_email: &PreparedEmail| match config.delivery {
+        DeliveryConfig::Sendmail { ref path, ref args } => (vec![
+            crate::worker::sendmail::sendmail_delivery(prepared_email, path, args);
+            prepared_email.to.len()
+        ], false),
+        DeliveryConfig::Lmtp(ref lmtp_config) =>
+            (crate::worker::lmtp::lmtp_delivery(prepared_email, lmtp_config, config), false),
+        DeliveryConfig::Discard { ref simulate } =>
+            (crate::worker::discard::discard_delivery(prepared_email, simulate), false),
+        _ => crate::worker::smtp::smtp_delivery(
+            prepared_email,
+            &mx_delivery.mx_server,
+            mx_delivery.mx_port,
+            source_addr,
+            config,
+            connection_limit),
+    };
+    let (results, unreachable) = match config.verp {
+        None => deliver(&mx_prepared_email),
+        Some(ref verp) => {
+            // Each recipient needs an envelope sender of their own, so a session
+            // of their own
+            let mut results: Vec<DeliveryResult> = Vec::new();
+            let mut unreachable = true;
+            for (address, r) in mx_prepared_email.to.iter().zip(&session_recipients) {
+                if let (true, Some(last)) = (unreachable, results.last().cloned()) {
+                    // No point connecting again
+                    results.push(last);
+                    continue;
+                }
+                let mut recipient_email = mx_prepared_email.clone();
+                recipient_email.to = vec![address.clone()];
+                match crate::verp::verp_address(verp, &internal_message_status.message_id, *r) {
+                    Some(from) => recipient_email.from = from,
+                    None => warn!("(worker) message-id {} is too long for a VERP address",
+                                  internal_message_status.message_id),
+                }
+                let (mut recipient_results, recipient_unreachable) = deliver(&recipient_email);
+                results.append(&mut recipient_results);
+                unreachable &= recipient_unreachable;
+            }
+            (results, unreachable)
+        }
+    };
 
     // Fix 'attempt' field in results on a per-recipient basis (not a per-mx basis)
-    for r in &mx_delivery.recipients {
-        // If the result is deferred, and the previous result was deferred, then
-        // bump the attempt number and update the reason message
-        if let DeliveryResult::Deferred(_, ref newmsg) = result {
-            deferred_some = true;
-            let mut data: Option<u8> = None;
-            if let DeliveryResult::Deferred(attempts, _) =
-                internal_message_status.recipients[*r].result
-            {
-                data = Some(attempts);
-            }
-            if data.is_some() {
-                let attempts = data.unwrap();
-                internal_message_status.recipients[*r].result =
-                    DeliveryResult::Deferred(attempts + 1, newmsg.clone());
-                continue;
+    for (r, result) in session_recipients.iter().zip(&results) {
+        // When delivering to MX servers, a recipient who failed at this server but
+        // has more servers left is deferred to the next one instead, and the next
+        // pass starts at the server after this one.
+        let mut result = result.clone();
+        if let DeliveryConfig::Remote(_) = config.delivery {
+            let recip = &mut internal_message_status.recipients[*r];
+            let position = recip.mx_servers.as_ref().and_then(|servers| {
+                servers.iter().position(|server| *server == mx_delivery.mx_server)
+                    .map(|index| (index, servers.len()))
+            });
+            if let Some((index, count)) = position {
+                let more_servers = index + 1 < count;
+                let failed_msg = match result {
+                    DeliveryResult::Failed(ref msg) if more_servers => Some(msg.clone()),
+                    _ => None,
+                };
+                if let Some(msg) = failed_msg {
+                    debug!("(worker) delivery to {} failed at {}, trying the next MX server.",
+                           recip.smtp_email_addr, mx_delivery.mx_server);
+                    result = DeliveryResult::Deferred(1, msg);
+                }
+                if let DeliveryResult::Deferred(..) = result {
+                    // Once every server has been tried, start over next time
+                    recip.current_mx = if more_servers { index + 1 } else { 0 };
+                }
             }
         }
 
-        // For everyone else, just take the result
-        internal_message_status.recipients[*r].result = result.clone();
+        // A deferral is numbered by this pass, whichever server it came from
+        if let DeliveryResult::Deferred(_, msg) = result {
+            result = DeliveryResult::Deferred(pass_attempts[*r], msg);
+        }
+        let recip = &mut internal_message_status.recipients[*r];
+        recip.failure_reason = match result {
+            DeliveryResult::Failed(_) if unreachable => Some(recip.unreachable_reason()),
+            DeliveryResult::Failed(_) => Some(FailureReason::Rejected),
+            _ => None,
+        };
+        recip.delivered_via = match result {
+            DeliveryResult::Delivered(_) => Some(mx_delivery.mx_server.clone()),
+            _ => None,
+        };
+        recip.result = result;
+    }
+
+    if let Some(ref listener) = config.listener {
+        for r in &session_recipients {
+            let recip = &internal_message_status.recipients[*r];
+            listener.on_result(&internal_message_status.message_id,
+                               &recip.smtp_email_addr, &recip.result);
+        }
     }
 
-    !deferred_some
+    // Suppress recipients whose mailbox does not exist.  Unless we got a result for
+    // each recipient, we only do this when the session had a single recipient, since
+    // we cannot tell which recipient was rejected otherwise.
+    if let Some(ref suppression_list) = config.suppression_list {
+        if per_recipient_results || mx_prepared_email.to.len() == 1 {
+            for (address, r) in mx_prepared_email.to.iter().zip(&session_recipients) {
+                let result = &internal_message_status.recipients[*r].result;
+                if let DeliveryResult::Failed(_) = *result {
+                    let user_unknown = result.enhanced_status_code()
+                        .map(|code| code.is_user_unknown())
+                        .unwrap_or(false);
+                    if user_unknown {
+                        info!("(worker) adding {} to the suppression list", address);
+                        suppression_list.insert(address);
+                    }
+                }
+            }
+        }
+    }
+
+    (session_recipients, unreachable)
 }
 
+// Whether a mail exchange name is an IP address literal rather than a hostname
 pub fn is_ip(s: &str) -> bool {
-    if let Some(last) = s.chars().rev().next() {
-        last.is_digit(10)
-    } else {
-        false
-    }
+    s.trim_end_matches('.').parse::<IpAddr>().is_ok()
 }
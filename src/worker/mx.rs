@@ -1,58 +1,172 @@
 use super::is_ip;
+use rand::Rng;
+use crate::config::RemoteDeliveryConfig;
+use crate::delivery_result::DeliveryResult;
 use crate::message_status::InternalMessageStatus;
+use crate::recipient_status::{InternalRecipientStatus, MxSource};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Instant, SystemTime};
 use trust_dns_resolver::Resolver;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::rr::{RData, RecordType};
 
-// Get MX records for email recipients
+// The servers for a domain, where they came from, and when they expire
+type MxLookup = Result<(Vec<String>, MxSource, Option<SystemTime>), String>;
+type LookupSlot = Mutex<Option<MxLookup>>;
+
+// Get MX records for the email recipients still waiting that do not have them yet,
+// looking up to `mx_lookup_concurrency` domains at a time.  Recipients whose domain
+// could not be looked up (as opposed to having no records) are deferred, and left
+// without MX servers so that the next pass looks again.
 pub fn get_mx_records_for_email(
     internal_message_status: &mut InternalMessageStatus,
     resolver: &Resolver,
+    rdc: &RemoteDeliveryConfig,
 ) {
-    // Look-up the MX records for each recipient
+    let needs_lookup = |r: &InternalRecipientStatus| r.mx_servers.is_none() && !r.result.completed();
+
+    // Look up each distinct domain once
+    let mut domains: Vec<String> = internal_message_status.recipients
+        .iter()
+        .filter(|r| needs_lookup(r))
+        .map(|r| r.domain.clone())
+        .collect();
+    domains.sort();
+    domains.dedup();
+
+    let results: Vec<LookupSlot> =
+        domains.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+
+    // Each thread takes the next unresolved domain until none are left. Results
+    // are stored by index, so completion order does not matter.
+    thread::scope(|scope| {
+        for _ in 0..rdc.mx_lookup_concurrency.max(1).min(domains.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= domains.len() {
+                    break;
+                }
+                let mx_records = get_mx_records_for_domain(&domains[i], resolver, rdc);
+                if let Ok(mut slot) = results[i].lock() {
+                    *slot = Some(mx_records);
+                }
+            });
+        }
+    });
+
     for recipient in &mut internal_message_status.recipients {
-        let mx_records = get_mx_records_for_domain(&*recipient.domain, resolver);
+        if !needs_lookup(recipient) {
+            continue;
+        }
+        let lookup = domains
+            .binary_search(&recipient.domain)
+            .ok()
+            .and_then(|i| results[i].lock().ok().and_then(|slot| slot.clone()))
+            .unwrap_or_else(|| Ok((vec![recipient.domain.clone()], MxSource::Unresolved, None)));
+        let (mx_records, mx_source, mx_expires_at) = match lookup {
+            Ok(found) => found,
+            Err(msg) => {
+                debug!("DEBUG: deferring {}: {}", recipient.email_addr, msg);
+                // Renumbered by the pass (see deliver_to_all_servers)
+                recipient.result = DeliveryResult::Deferred(recipient.attempts, msg);
+                recipient.mx_servers = None;
+                recipient.mx_source = None;
+                recipient.mx_expires_at = None;
+                continue;
+            }
+        };
         recipient.mx_servers = Some(mx_records);
+        recipient.mx_source = Some(mx_source);
+        recipient.mx_expires_at = mx_expires_at;
+        recipient.current_mx = 0;
         debug!(
-            "DEBUG: got mx servers for {}: {:?}",
+            "DEBUG: got mx servers for {} ({:?}): {:?}",
             recipient.email_addr,
+            mx_source,
             recipient.mx_servers.as_ref().unwrap()
         );
     }
 }
 
-// Get MX records for a domain, in order of preference
-fn get_mx_records_for_domain(domain: &str, resolver: &Resolver) -> Vec<String> {
-    use std::cmp::Ordering;
-
-    let response = match resolver.mx_lookup(domain) {
-        Ok(res) => res,
-        Err(_) => {
-            // fallback to the domain (RFC 5321)
-            return vec![domain.to_owned()];
-        }
-    };
-
-    let mut records: Vec<(u16, String)> = response
+// Get MX records for a domain, in order of preference.  Err if the lookup failed
+// (rather than finding no records), with why.
+pub fn get_mx_records_for_domain(domain: &str, resolver: &Resolver, rdc: &RemoteDeliveryConfig)
+                             -> MxLookup
+{
+    // Configured servers are used as they are, in the order given
+    let overridden = rdc.mx_overrides
         .iter()
-        .map(|mx| (mx.preference(), mx.exchange().to_string()))
-        .collect();
+        .find(|&(name, _)| name.trim_end_matches('.').eq_ignore_ascii_case(domain));
+    if let Some((_, servers)) = overridden {
+        return Ok((servers.clone(), MxSource::Override, None));
+    }
+
+    let (records, expires_at): (Vec<(u16, String)>, _) =
+        match resolver.lookup(domain, RecordType::MX) {
+            Ok(response) => (
+                response
+                    .iter()
+                    .filter_map(|rdata| match *rdata {
+                        RData::MX(ref mx) => Some((mx.preference(), mx.exchange().to_string())),
+                        _ => None,
+                    })
+                    .collect(),
+                to_system_time(response.valid_until()),
+            ),
+            Err(ref e) if is_no_records(e) => return implicit_mx(domain, resolver),
+            Err(e) => return Err(format!("DNS lookup of MX records for {} failed: {}", domain, e)),
+        };
 
     if records.is_empty() {
-        // fallback to the domain (RFC 5321)
-        return vec![domain.to_owned()];
+        return implicit_mx(domain, resolver);
     }
 
+    Ok((order_mx_records(records), MxSource::Mx, Some(expires_at)))
+}
+
+// When a lookup's records expire, as a time that can be stored
+fn to_system_time(valid_until: Instant) -> SystemTime {
+    SystemTime::now() + valid_until.saturating_duration_since(Instant::now())
+}
+
+// Whether a lookup found the name has no records of that type (or does not exist)
+fn is_no_records(e: &ResolveError) -> bool {
+    matches!(*e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+// Put MX records (preference, exchange) in the order to try them, and strip the
+// trailing dots off the names
+pub fn order_mx_records(mut records: Vec<(u16, String)>) -> Vec<String> {
+    use std::cmp::Ordering;
+
     // Sort by priority
     records.sort_by(|a, b| a.0.cmp(&b.0));
 
-    // Move any results that end in a digit to the end (domain names are preferred
-    // over IP addresses, regardless of their MX setting, due to the inability to
-    // verify certificates with IP addresses)
+    // Servers of equal preference are tried in random order, to spread the load
+    // between them (RFC 5321 section 5.1)
+    let mut rng = rand::thread_rng();
+    let mut start = 0;
+    while start < records.len() {
+        let end = records[start..]
+            .iter()
+            .position(|r| r.0 != records[start].0)
+            .map_or(records.len(), |n| start + n);
+        rng.shuffle(&mut records[start..end]);
+        start = end;
+    }
+
+    // Move any IP addresses to the end (domain names are preferred over IP
+    // addresses, regardless of their MX setting, due to the inability to verify
+    // certificates with IP addresses)
     records.sort_by(|a, b| {
         let a_is_ip = is_ip(&*(a.1));
         let b_is_ip = is_ip(&*(b.1));
         match (a_is_ip, b_is_ip) {
-            (true, false) => Ordering::Less,
-            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
             _ => Ordering::Equal,
         }
     });
@@ -62,3 +176,22 @@ fn get_mx_records_for_domain(domain: &str, resolver: &Resolver) -> Vec<String> {
         .map(|(_, exch)| exch.trim_end_matches(|c| c == '.').to_owned())
         .collect()
 }
+
+// Without MX records, the domain itself is the implicit MX if it has an A or AAAA
+// record (RFC 5321 section 5.1)
+fn implicit_mx(domain: &str, resolver: &Resolver) -> MxLookup {
+    let (has_address, expires_at) = match resolver.lookup_ip(domain) {
+        Ok(response) => (response.iter().next().is_some(),
+                         Some(to_system_time(response.valid_until()))),
+        Err(ref e) if is_no_records(e) => (false, None),
+        Err(e) => return Err(format!("DNS lookup of addresses for {} failed: {}", domain, e)),
+    };
+
+    if has_address {
+        Ok((vec![domain.to_owned()], MxSource::ImplicitAddress, expires_at))
+    } else {
+        // Still try the domain and let the connection attempt report what is wrong
+        warn!("No MX or A/AAAA records found for {}", domain);
+        Ok((vec![domain.to_owned()], MxSource::Unresolved, None))
+    }
+}
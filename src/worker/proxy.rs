@@ -0,0 +1,212 @@
+use crate::config::{ProxyConfig, ProxyProtocol};
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Open a TCP connection to (host, port) tunneled through the configured proxy.
+// Name resolution of `host` is left to the proxy.
+pub fn connect_via_proxy(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+    source_addr: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<TcpStream, IoError> {
+    // The proxy being unreachable is an outage on our side, so these errors
+    // all defer delivery rather than failing it
+    let addrs: Vec<SocketAddr> = match (&*proxy.host, proxy.port).to_socket_addrs() {
+        Ok(iter) => iter.collect(),
+        Err(e) => return Err(transient_proxy_error(format!(
+            "Could not resolve proxy ({}, {}): {}", proxy.host, proxy.port, e))),
+    };
+    let proxy_addr = match (crate::worker::smtp::pick_sockaddr(
+        addrs.iter().cloned(), source_addr), source_addr)
+    {
+        (Some(sa), _) => sa,
+        (None, Some(source)) if !addrs.is_empty() => return Err(transient_proxy_error(format!(
+            "No addresses of proxy ({}, {}) are in the same address family (IPv{}) \
+             as the source address {}",
+            proxy.host, proxy.port, if source.is_ipv4() { 4 } else { 6 }, source))),
+        (None, _) => return Err(transient_proxy_error(format!(
+            "No SockAddrs for proxy ({}, {})", proxy.host, proxy.port))),
+    };
+
+    let mut stream = crate::worker::smtp::connect_tcp(&proxy_addr, source_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    match proxy.protocol {
+        ProxyProtocol::Socks5 => socks5_connect(&mut stream, proxy, host, port)?,
+        ProxyProtocol::HttpConnect => http_connect(&mut stream, proxy, host, port)?,
+    }
+
+    debug!("(worker) connected to {}:{} via proxy {}:{}", host, port, proxy.host, proxy.port);
+
+    Ok(stream)
+}
+
+// RFC 1928 (SOCKS5) and RFC 1929 (username/password authentication)
+fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> Result<(), IoError> {
+    const NO_AUTH: u8 = 0x00;
+    const USERNAME_PASSWORD: u8 = 0x02;
+
+    // Offer the methods we can handle
+    if proxy.auth.is_some() {
+        stream.write_all(&[5, 2, NO_AUTH, USERNAME_PASSWORD])?;
+    } else {
+        stream.write_all(&[5, 1, NO_AUTH])?;
+    }
+
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(proxy_error("SOCKS5 proxy sent an invalid reply".to_owned()));
+    }
+    match (reply[1], &proxy.auth) {
+        (NO_AUTH, _) => {}
+        (USERNAME_PASSWORD, Some(auth)) => {
+            let username = auth.username.as_bytes();
+            let password = auth.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("SOCKS5 username or password too long".to_owned()));
+            }
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request)?;
+
+            let mut reply = [0_u8; 2];
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(proxy_error("SOCKS5 proxy authentication failed".to_owned()));
+            }
+        }
+        _ => return Err(proxy_error(
+            "SOCKS5 proxy accepted none of our authentication methods".to_owned())),
+    }
+
+    // CONNECT request
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(proxy_error(format!("Hostname too long for SOCKS5: {}", host)));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0_u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(socks5_reply_error(reply[1]));
+    }
+
+    // Discard the bound address
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0_u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(proxy_error("SOCKS5 proxy sent an invalid address type".to_owned())),
+    };
+    let mut bound = vec![0_u8; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+fn socks5_reply_error(code: u8) -> IoError {
+    // Map to kinds that get the same deferred/failed treatment as a direct connection
+    let (kind, msg) = match code {
+        1 => (ErrorKind::ConnectionAborted, "general SOCKS server failure"),
+        2 => (ErrorKind::ConnectionRefused, "connection not allowed by ruleset"),
+        3 => (ErrorKind::ConnectionAborted, "network unreachable"),
+        4 => (ErrorKind::ConnectionAborted, "host unreachable"),
+        5 => (ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (ErrorKind::TimedOut, "TTL expired"),
+        7 => (ErrorKind::Other, "command not supported"),
+        8 => (ErrorKind::Other, "address type not supported"),
+        _ => (ErrorKind::ConnectionAborted, "unknown error"),
+    };
+    IoError::new(kind, format!("SOCKS5 proxy: {}", msg))
+}
+
+fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> Result<(), IoError> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(ref auth) = proxy.auth {
+        let credentials = base64::encode(&format!("{}:{}", auth.username, auth.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // Read the response header a byte at a time, so we don't consume any of
+    // the SMTP greeting that follows it
+    let mut response: Vec<u8> = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(proxy_error("HTTP proxy response header too long".to_owned()));
+        }
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    let status = status_line.split_whitespace().nth(1).unwrap_or("");
+    match status {
+        s if s.starts_with('2') => {}
+        // Bad Gateway, Service Unavailable, Gateway Timeout: the proxy or the
+        // path beyond it is having trouble, which may pass
+        "502" | "503" => return Err(IoError::new(ErrorKind::ConnectionAborted,
+            format!("HTTP proxy could not CONNECT: {}", status_line))),
+        "504" => return Err(IoError::new(ErrorKind::TimedOut,
+            format!("HTTP proxy could not CONNECT: {}", status_line))),
+        _ => return Err(proxy_error(format!("HTTP proxy refused CONNECT: {}", status_line))),
+    }
+
+    Ok(())
+}
+
+// A protocol or authentication refusal by the proxy, which fails delivery
+fn proxy_error(msg: String) -> IoError {
+    IoError::other(msg)
+}
+
+// A proxy problem that may pass, which defers delivery
+fn transient_proxy_error(msg: String) -> IoError {
+    IoError::new(ErrorKind::ConnectionAborted, msg)
+}
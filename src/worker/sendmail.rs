@@ -0,0 +1,116 @@
+use crate::delivery_result::{DeliveredResponse, DeliveryResult};
+use crate::prepared_email::PreparedEmail;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Exit codes from sysexits.h that mean the message will never be accepted:
+// EX_USAGE, EX_DATAERR, EX_NOINPUT, EX_NOUSER, EX_NOHOST and EX_NOPERM
+const PERMANENT_FAILURES: [i32; 6] = [64, 65, 66, 67, 68, 77];
+
+// Hand an email to a local sendmail binary, run as
+// `path <args> -f <sender> -- <recipients>`
+pub fn sendmail_delivery(
+    prepared_email: &PreparedEmail,
+    path: &Path,
+    args: &[String],
+) -> DeliveryResult {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "sendmail_delivery",
+        message_id = %prepared_email.message_id,
+        recipients = %prepared_email.to.join(", "),
+    ).entered();
+
+    let mut child = match Command::new(path)
+        .args(args)
+        .arg("-f")
+        .arg(&prepared_email.from)
+        .arg("--")
+        .args(&prepared_email.to)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            info!("(worker) could not run {}: {}", path.display(), e);
+            return DeliveryResult::Deferred(1, format!("Could not run {}: {}", path.display(), e));
+        }
+    };
+
+    // sendmail expects local (LF) line endings.  Every other byte is passed on as
+    // it is, since the message need not be UTF-8.
+    let message = lf_line_endings(&prepared_email.message);
+    let write_result = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(&message),
+        None => Ok(()),
+    };
+
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = pipe.read_to_string(&mut stderr);
+    }
+    let stderr = stderr.trim();
+
+    let status = match child.wait() {
+        Ok(status) => status,
+        Err(e) => return DeliveryResult::Deferred(
+            1, format!("Could not wait for {}: {}", path.display(), e)),
+    };
+
+    let describe = |what: String| -> String {
+        if stderr.is_empty() {
+            what
+        } else {
+            format!("{}: {}", what, stderr)
+        }
+    };
+
+    match status.code() {
+        Some(0) => {
+            if let Err(e) = write_result {
+                // It exited successfully, but may not have all of the message
+                return DeliveryResult::Deferred(
+                    1, format!("Could not write message to {}: {}", path.display(), e));
+            }
+            DeliveryResult::Delivered(DeliveredResponse {
+                code: 0,
+                message: describe(format!("Accepted by {}", path.display())),
+                mx_host: path.display().to_string(),
+                encrypted: false,
+                used_helo: false,
+                dry_run: false,
+            })
+        }
+        Some(code) if PERMANENT_FAILURES.contains(&code) => {
+            info!("(worker) {} failed with exit code {}", path.display(), code);
+            DeliveryResult::Failed(describe(format!("{} exited with {}", path.display(), code)))
+        }
+        Some(code) => {
+            // EX_TEMPFAIL, EX_UNAVAILABLE, EX_OSERR, EX_IOERR and anything unknown
+            info!("(worker) {} deferred with exit code {}", path.display(), code);
+            DeliveryResult::Deferred(
+                1, describe(format!("{} exited with {}", path.display(), code)))
+        }
+        None => {
+            info!("(worker) {} was killed by a signal", path.display());
+            DeliveryResult::Deferred(
+                1, describe(format!("{} was killed by a signal", path.display())))
+        }
+    }
+}
+
+// Convert CRLF line endings to LF
+fn lf_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(message.len());
+    let mut bytes = message.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+            continue;
+        }
+        converted.push(byte);
+    }
+    converted
+}
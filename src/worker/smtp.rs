@@ -1,25 +1,75 @@
-use crate::config::{Config, DeliveryConfig};
-use crate::delivery_result::DeliveryResult;
+use crate::config::{
+    Config, DeliveryConfig, DsnNotify, DsnRequest, DsnReturn, RelayConfig
+};
+use crate::delivery_result::{DeliveredResponse, DeliveryResult};
 use crate::prepared_email::PreparedEmail;
+use crate::worker::limit::ConnectionLimit;
 use lettre::smtp::authentication::Credentials;
-use lettre::smtp::client::net::ClientTlsParameters;
+use lettre::smtp::client::net::{ClientTlsParameters, Connector, NetworkStream, Timeout};
+use lettre::smtp::client::InnerClient;
+use lettre::smtp::commands::{
+    DataCommand, EhloCommand, MailCommand, RcptCommand, RsetCommand, StarttlsCommand
+};
 use lettre::smtp::error::Error as LettreSmtpError;
-use lettre::smtp::extension::ClientId;
-use lettre::smtp::response::Severity;
-use lettre::smtp::{ClientSecurity, SmtpClient};
-use lettre::Transport;
-use native_tls::{TlsConnector, Protocol};
-use std::net::ToSocketAddrs;
+use lettre::smtp::extension::{
+    ClientId, Extension, MailBodyParameter, MailParameter, RcptParameter, ServerInfo
+};
+use lettre::smtp::response::{Category, Detail, Response, Severity};
+use lettre::smtp::ClientSecurity;
+use lettre::{EmailAddress, SendableEmail};
+use native_tls::{TlsConnector, TlsStream};
+use socket2::{Domain, Socket, Type};
+use std::cell::Cell;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::rc::Rc;
 use std::time::Duration;
-use std::io::ErrorKind;
+use std::io::{Cursor, ErrorKind, Read, Write};
 
-// Deliver an email to an SMTP server
+// The port for SMTP over implicit TLS (RFC 8314)
+const SMTPS_PORT: u16 = 465;
+
+// The lettre client errors that say the connection went away or was never made,
+// rather than that something is wrong with the email or our configuration
+const TRANSIENT_CLIENT_ERRORS: &[&str] = &[
+    "Connection closed",
+    "is not connected anymore",
+    "Could not resolve hostname",
+];
+
+// Deliver an email to an SMTP server.  Returns one result per recipient in
+// `prepared_email.to`, in the same order, since the server may accept some
+// recipients and reject others.  Also returns whether the server could not be
+// connected to at all.
 pub fn smtp_delivery(
     prepared_email: &PreparedEmail,
     smtp_server_domain: &str,
     port: u16,
-    config: &Config
-) -> DeliveryResult {
+    source_addr: Option<IpAddr>,
+    config: &Config,
+    connection_limit: &ConnectionLimit,
+) -> (Vec<DeliveryResult>, bool) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "smtp_delivery",
+        message_id = %prepared_email.message_id,
+        recipients = %prepared_email.to.join(", "),
+        mx = %smtp_server_domain,
+        port,
+    ).entered();
+
+    let all = |result: DeliveryResult| vec![result; prepared_email.to.len()];
+
+    // Introduce ourselves by the name that goes with the address we send from
+    let mapped_config;
+    let config = match source_addr.and_then(|addr| config.helo_map.get(&addr)) {
+        Some(helo_name) => {
+            mapped_config = Config { helo_name: helo_name.clone(), ..config.clone() };
+            &mapped_config
+        }
+        None => config,
+    };
 
     // lettre::EmailAddress checks validity.  But we checked that when we created
     // PreparedEmail so this conversion should always pass.
@@ -27,26 +77,40 @@ pub fn smtp_delivery(
         Ok(se) => se,
         Err(e) => {
             warn!("Invalid email address error: {:?}", e);
-            return DeliveryResult::Failed(format!("Invalid email address error: {:?}", e));
+            return (all(DeliveryResult::Failed(format!("Invalid email address error: {:?}", e))),
+                    false);
         }
     };
 
     let tls_builder = match TlsConnector::builder()
-        .min_protocol_version(Some(Protocol::Tlsv12))
+        .min_protocol_version(Some(config.min_tls_version.protocol()))
+        .danger_accept_invalid_certs(config.danger_accept_invalid_certs)
+        .danger_accept_invalid_hostnames(config.danger_accept_invalid_hostnames)
         .build()
     {
         Ok(connector) => connector,
         Err(e) => {
             info!("(worker) failed to create TLS Connector: {:?}", e);
-            return DeliveryResult::Failed(format!("Failed to create TLS connector: {:?}", e));
+            return (all(DeliveryResult::Failed(
+                format!("Failed to create TLS connector: {:?}", e))), false);
         }
     };
 
-    let client_security = if let DeliveryConfig::Relay(ref rc) = config.delivery {
+    // With implicit TLS (SMTPS) the connection is encrypted before the session
+    // starts, so there is no STARTTLS
+    let implicit_tls = match config.delivery {
+        DeliveryConfig::Relay(ref rc) => rc.implicit_tls || rc.port == Some(SMTPS_PORT),
+        _ => false,
+    };
+    let implicit_tls_connector = if implicit_tls { Some(tls_builder.clone()) } else { None };
+
+    let client_security = if implicit_tls {
+        ClientSecurity::None
+    } else if let DeliveryConfig::Relay(ref rc) = config.delivery {
         if rc.use_tls {
             let tls_parameters =
                 ClientTlsParameters::new(smtp_server_domain.to_owned(), tls_builder);
-            if config.require_tls {
+            if config.relay_require_tls.unwrap_or(config.require_tls) {
                 ClientSecurity::Required(tls_parameters)
             } else {
                 ClientSecurity::Opportunistic(tls_parameters)
@@ -58,13 +122,208 @@ pub fn smtp_delivery(
         let tls_parameters =
             ClientTlsParameters::new(smtp_server_domain.to_owned(), tls_builder);
 
-        if config.require_tls {
+        if config.remote_require_tls.unwrap_or(config.require_tls) {
             ClientSecurity::Required(tls_parameters)
         } else {
             ClientSecurity::Opportunistic(tls_parameters)
         }
     };
 
+    let timeout = Duration::from_secs(config.smtp_timeout_secs);
+
+    // Wait for our turn if too many connections are already open (in all, or to
+    // the recipients' domains when delivering to them directly).  This is held
+    // until the session is over.
+    let domains: Vec<String> = match config.delivery {
+        DeliveryConfig::Remote(_) => prepared_email.to
+            .iter()
+            .filter_map(|address| address.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase())
+            .collect(),
+        _ => vec![],
+    };
+    let _permit = connection_limit.acquire(&domains);
+
+    if let DeliveryConfig::Relay(RelayConfig { unix_socket: Some(ref path), .. }) = config.delivery {
+        debug!("Starting SMTP delivery to [{}] at {}",
+               prepared_email.to.join(", "), path.display());
+        let (results, unreachable) = unix_smtp_delivery(sendable_email, path, config, timeout);
+        let mx_host = path.display().to_string();
+        let results = results.into_iter().map(|r| note_mx_host(r, &mx_host)).collect();
+        return (results, unreachable);
+    }
+
+    debug!(
+        "Starting SMTP delivery to [{}] at {}",
+        prepared_email.to.join(", "),
+        smtp_server_domain
+    );
+
+    // Open the connection ourselves (rather than letting lettre do it) so that we
+    // control how the socket is created
+    let (results, unreachable) =
+        match open_stream(smtp_server_domain, port, source_addr, config, timeout)
+    {
+        Ok(stream) => {
+            let stream = match implicit_tls_connector {
+                Some(connector) => tls_connect(&connector, smtp_server_domain, stream, timeout)
+                    .map(NetworkStream::Tls),
+                None => Ok(NetworkStream::Tcp(stream)),
+            };
+            match stream {
+                Ok(stream) => {
+                    let mut client: InnerClient = InnerClient::new();
+                    client.set_stream(stream);
+
+                    let results = smtp_session_results(
+                        &mut client, sendable_email, &client_security, config, timeout);
+
+                    // Send QUIT (if the connection is still up) and drop the connection
+                    client.close();
+
+                    (results, false)
+                }
+                Err(e) => {
+                    // Like a failed STARTTLS handshake, this is a transport problem,
+                    // so try the next server rather than bouncing the message
+                    info!("(worker) TLS handshake with {} failed, deferring: {}",
+                          smtp_server_domain, e);
+                    (all(DeliveryResult::Deferred(1, format!("TLS handshake failed: {}", e))),
+                     true)
+                }
+            }
+        },
+        Err(result) => (all(result), true),
+    };
+
+    // Note which server accepted the message, and which local address we sent from
+    // (for debugging)
+    let results = results.into_iter()
+        .map(|r| note_mx_host(r, smtp_server_domain))
+        .map(|r| match source_addr {
+            Some(addr) => note_source_addr(r, addr),
+            None => r,
+        })
+        .collect();
+    (results, unreachable)
+}
+
+// Deliver over a Unix domain socket (to a local relay), without TLS
+#[cfg(unix)]
+fn unix_smtp_delivery(
+    sendable_email: SendableEmail,
+    path: &Path,
+    config: &Config,
+    timeout: Duration,
+) -> (Vec<DeliveryResult>, bool) {
+    let stream = match crate::worker::unix::UnixSocketStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => return (vec![classify_result(Err(LettreSmtpError::Io(e)));
+                               sendable_email.envelope().to().len()], true),
+    };
+    let mut client = InnerClient::new();
+    client.set_stream(stream);
+
+    let results = smtp_session_results(
+        &mut client, sendable_email, &ClientSecurity::None, config, timeout);
+    client.close();
+
+    (results, false)
+}
+
+#[cfg(not(unix))]
+fn unix_smtp_delivery(
+    sendable_email: SendableEmail,
+    _path: &Path,
+    _config: &Config,
+    _timeout: Duration,
+) -> (Vec<DeliveryResult>, bool) {
+    (vec![DeliveryResult::Failed("Unix sockets are not supported on this platform".to_owned());
+          sendable_email.envelope().to().len()], false)
+}
+
+// Start TLS on a new connection, for implicit TLS
+fn tls_connect(
+    connector: &TlsConnector,
+    domain: &str,
+    stream: TcpStream,
+    timeout: Duration,
+) -> Result<TlsStream<TcpStream>, String> {
+    // Do not wait forever for the handshake
+    stream.set_read_timeout(Some(timeout)).map_err(|e| format!("{:?}", e))?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| format!("{:?}", e))?;
+    connector.connect(domain, stream).map_err(|e| format!("{:?}", e))
+}
+
+// Note which server accepted the message
+pub fn note_mx_host(result: DeliveryResult, mx_host: &str) -> DeliveryResult {
+    match result {
+        DeliveryResult::Delivered(mut response) => {
+            response.mx_host = mx_host.to_owned();
+            DeliveryResult::Delivered(response)
+        }
+        other => other,
+    }
+}
+
+fn note_source_addr(result: DeliveryResult, addr: IpAddr) -> DeliveryResult {
+    match result {
+        DeliveryResult::Queued => DeliveryResult::Queued,
+        DeliveryResult::Deferred(attempts, msg) =>
+            DeliveryResult::Deferred(attempts, format!("{} (from {})", msg, addr)),
+        DeliveryResult::Delivered(mut response) => {
+            response.message = format!("{} (from {})", response.message, addr);
+            DeliveryResult::Delivered(response)
+        }
+        DeliveryResult::Failed(msg) =>
+            DeliveryResult::Failed(format!("{} (from {})", msg, addr)),
+    }
+}
+
+// Pick the first address we can reach from the source address (we cannot connect
+// to an IPv6 address from an IPv4 source or vice versa)
+pub fn pick_sockaddr<I>(addrs: I, source_addr: Option<IpAddr>) -> Option<SocketAddr>
+    where I: Iterator<Item = SocketAddr>
+{
+    let mut addrs = addrs;
+    match source_addr {
+        Some(source) => addrs.find(|sa| sa.is_ipv4() == source.is_ipv4()),
+        None => addrs.next(),
+    }
+}
+
+// Connect a TCP socket, first binding it to the source address if there is one
+pub fn connect_tcp(
+    addr: &SocketAddr,
+    source_addr: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<TcpStream, ::std::io::Error> {
+    match source_addr {
+        None => TcpStream::connect_timeout(addr, timeout),
+        Some(source) => {
+            let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+            socket.bind(&SocketAddr::new(source, 0).into())?;
+            socket.connect_timeout(&(*addr).into(), timeout)?;
+            Ok(socket.into())
+        }
+    }
+}
+
+// Open a TCP connection to the SMTP server, either directly or through the
+// configured proxy
+fn open_stream(
+    smtp_server_domain: &str,
+    port: u16,
+    source_addr: Option<IpAddr>,
+    config: &Config,
+    timeout: Duration,
+) -> Result<TcpStream, DeliveryResult> {
+    if let Some(ref proxy) = config.proxy {
+        return crate::worker::proxy::connect_via_proxy(
+            proxy, smtp_server_domain, port, source_addr, timeout)
+            .map_err(|e| classify_result(Err(LettreSmtpError::Io(e))));
+    }
+
     // Build sockaddr
     let sockaddr = match (smtp_server_domain, port).to_socket_addrs() {
         Err(e) => {
@@ -72,66 +331,524 @@ pub fn smtp_delivery(
                 "ToSocketAddr failed for ({}, {}): {:?}",
                 smtp_server_domain, port, e
             );
-            return DeliveryResult::Failed(format!(
+            return Err(DeliveryResult::Failed(format!(
                 "ToSockaddr failed for ({}, {}): {:?}",
                 smtp_server_domain, port, e
-            ));
-        }
-        Ok(mut iter) => match iter.next() {
-            Some(sa) => sa,
-            None => {
-                warn!("No SockAddrs for ({}, {})", smtp_server_domain, port);
-                return DeliveryResult::Failed(format!(
-                    "No SockAddrs for ({}, {})",
-                    smtp_server_domain, port
-                ));
+            )));
+        }
+        Ok(iter) => {
+            let addrs: Vec<SocketAddr> = iter.collect();
+            match (pick_sockaddr(addrs.iter().cloned(), source_addr), source_addr) {
+                (Some(sa), _) => sa,
+                (None, Some(source)) if !addrs.is_empty() => {
+                    // Our configuration, not the server, is the problem, and it may
+                    // be reachable from another source address another time
+                    warn!("No addresses of ({}, {}) can be reached from source address {}",
+                          smtp_server_domain, port, source);
+                    return Err(DeliveryResult::Deferred(1, format!(
+                        "No addresses of ({}, {}) are in the same address family \
+                         (IPv{}) as the source address {}",
+                        smtp_server_domain, port, if source.is_ipv4() { 4 } else { 6 },
+                        source
+                    )));
+                }
+                (None, _) => {
+                    warn!("No SockAddrs for ({}, {})", smtp_server_domain, port);
+                    return Err(DeliveryResult::Failed(format!(
+                        "No SockAddrs for ({}, {})",
+                        smtp_server_domain, port
+                    )));
+                }
             }
         },
     };
 
-    let mailer = match SmtpClient::new(sockaddr, client_security) {
-        Ok(m) => m,
-        Err(e) => {
-            info!("(worker) failed to setup SMTP transport: {:?}", e);
-            return DeliveryResult::Failed(format!("Unable to setup SMTP transport: {:?}", e));
+    connect_tcp(&sockaddr, source_addr, timeout)
+        .map_err(|e| classify_result(Err(LettreSmtpError::Io(e))))
+}
+
+// Run the SMTP conversation, giving each recipient their own result
+fn smtp_session_results<S>(
+    client: &mut InnerClient<S>,
+    sendable_email: SendableEmail,
+    client_security: &ClientSecurity,
+    config: &Config,
+    timeout: Duration,
+) -> Vec<DeliveryResult>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    let mut results: Vec<Option<DeliveryResult>> = vec![None; sendable_email.envelope().to().len()];
+
+    let mut used_helo = false;
+    if let Err(e) = smtp_session(client, sendable_email, client_security, config, timeout,
+                                 &mut results, &mut used_helo)
+    {
+        // Everyone who did not get a reply of their own gets the session error
+        let result = classify_result(Err(e));
+        for r in results.iter_mut().filter(|r| r.is_none()) {
+            *r = Some(result.clone());
         }
+    }
+
+    // Every entry is filled in by now
+    let encrypted = client.is_encrypted();
+    results.into_iter()
+        .map(|r| match r.unwrap() {
+            DeliveryResult::Delivered(response) =>
+                DeliveryResult::Delivered(DeliveredResponse { encrypted, used_helo, ..response }),
+            other => other,
+        })
+        .collect()
+}
+
+// Run the SMTP conversation over an established connection, from the server
+// greeting through to the end of DATA (or RSET, for a dry run).  `used_helo` is
+// set if the server only accepted HELO.
+fn smtp_session<S>(
+    client: &mut InnerClient<S>,
+    sendable_email: SendableEmail,
+    client_security: &ClientSecurity,
+    config: &Config,
+    timeout: Duration,
+    results: &mut [Option<DeliveryResult>],
+    used_helo: &mut bool,
+) -> Result<(), LettreSmtpError>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    client.set_timeout(Some(timeout))?;
+
+    read_reply(client)?;
+
+    let client_id = ClientId::Domain(config.helo_name.to_owned());
+    let mut ehlo_response = match client.command(EhloCommand::new(client_id.clone())) {
+        // Old servers may not understand EHLO, and broken ones may garble the reply
+        Err(e @ LettreSmtpError::Permanent(_)) | Err(e @ LettreSmtpError::ResponseParsing(_))
+            if config.allow_helo_fallback =>
+        {
+            info!("(worker) EHLO failed, trying HELO: {:?}", e);
+            *used_helo = true;
+            client.command(HeloCommand(client_id.clone()))?
+        }
+        response => response?,
     };
+    let mut server_info = ServerInfo::from_response(&ehlo_response)?;
+    debug!("(worker) server {}", server_info);
 
-    // Configure the mailer
-    let mut mailer = mailer
-        // FIXME, our helo_name is unnecessarily limiting.
-        .hello_name( ClientId::Domain(config.helo_name.to_owned()) )
-        .smtp_utf8(true) // is only used if the server supports it
-        .timeout(Some(Duration::from_secs( config.smtp_timeout_secs )));
+    // Not being able to encrypt when it is required is a failure, unless configured
+    // otherwise.  But a handshake that fails may work next time, so that is deferred.
+    let defer_everyone = |results: &mut [Option<DeliveryResult>], msg: String| {
+        for result in results.iter_mut() {
+            *result = Some(DeliveryResult::Deferred(1, msg.clone()));
+        }
+    };
+    match (client_security, server_info.supports_feature(Extension::StartTls)) {
+        (&ClientSecurity::Required(_), false) if config.defer_when_tls_unavailable => {
+            info!("(worker) server does not offer STARTTLS, deferring");
+            defer_everyone(results, "Could not encrypt connection: the server does not \
+                                     offer STARTTLS".to_owned());
+            return Ok(());
+        }
+        (&ClientSecurity::Required(_), false) => {
+            return Err(LettreSmtpError::Client("Could not encrypt connection, aborting"));
+        }
+        (&ClientSecurity::Opportunistic(ref tls_parameters), true)
+            | (&ClientSecurity::Required(ref tls_parameters), true) =>
+        {
+            client.command(StarttlsCommand)?;
+            match client.upgrade_tls_stream(tls_parameters) {
+                Ok(()) => {}
+                Err(e) if matches!(client_security, &ClientSecurity::Required(_)) => {
+                    info!("(worker) TLS handshake failed, deferring: {:?}", e);
+                    defer_everyone(results, format!("TLS handshake failed: {:?}", e));
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+            debug!("(worker) connection encrypted");
+            ehlo_response = client.command(EhloCommand::new(client_id))?;
+            server_info = ServerInfo::from_response(&ehlo_response)?;
+        }
+        _ => {}
+    }
 
     if let DeliveryConfig::Relay(ref relay_config) = config.delivery {
         if let Some(ref auth) = relay_config.auth {
-            mailer = mailer
-                .authentication_mechanism(auth.mechanism)
-                .credentials(Credentials::new(
+            if server_info.supports_auth_mechanism(auth.mechanism) {
+                client.auth(auth.mechanism, &Credentials::new(
                     auth.username.clone(),
                     auth.password.clone()
-                ));
+                ))?;
+            } else {
+                // Sending without authenticating would only be rejected, less clearly
+                info!("(worker) server does not support {} authentication", auth.mechanism);
+                return Err(LettreSmtpError::Client(
+                    "No compatible authentication mechanism was found"));
+            }
         }
     }
 
-    let mut mailer = mailer.transport();
+    let mut mail_parameters = vec![];
+    if server_info.supports_feature(Extension::EightBitMime) {
+        mail_parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    // SMTPUTF8 is only used if the server supports it
+    if server_info.supports_feature(Extension::SmtpUtfEight) {
+        mail_parameters.push(MailParameter::SmtpUtfEight);
+    }
 
-    const IGNORED_ATTEMPTS: u8 = 1;
+    // lettre's ServerInfo ignores the DSN keyword, so look for it ourselves
+    let dsn = match config.dsn {
+        Some(ref dsn) if supports_keyword(&ehlo_response, "DSN") => Some(dsn),
+        Some(_) => {
+            debug!("(worker) server does not support DSN, not requesting one");
+            None
+        },
+        None => None,
+    };
+    if let Some(dsn) = dsn {
+        mail_parameters.extend(dsn_mail_parameters(dsn, sendable_email.message_id()));
+    }
 
-    debug!(
-        "Starting SMTP delivery to [{}] at {}",
-        prepared_email.to.join(", "),
-        smtp_server_domain
-    );
+    let envelope = sendable_email.envelope().clone();
+
+    // Internationalized addresses can only be sent to servers that support SMTPUTF8
+    // (RFC 6531 section 3.2).  Their domains are already in ASCII form, so this is
+    // only about local parts.
+    if !server_info.supports_feature(Extension::SmtpUtfEight) {
+        let needs_utf8 = |address: &EmailAddress| !address.to_string().is_ascii();
+        let unsupported = |address: &EmailAddress| DeliveryResult::Failed(format!(
+            "5.6.7 The server does not support SMTPUTF8, which {} needs", address));
+        if let Some(from) = envelope.from().filter(|from| needs_utf8(from)) {
+            for result in results.iter_mut() {
+                *result = Some(unsupported(from));
+            }
+            return Ok(());
+        }
+        for (result, to_address) in results.iter_mut().zip(envelope.to()) {
+            if needs_utf8(to_address) {
+                *result = Some(unsupported(to_address));
+            }
+        }
+    }
+    // The recipients to send RCPT commands for
+    let rcpt_indices: Vec<usize> = (0..results.len()).filter(|i| results[*i].is_none()).collect();
+    if rcpt_indices.is_empty() {
+        return Ok(());
+    }
+
+    // lettre's ServerInfo ignores CHUNKING too.  BDAT chunks are written as text,
+    // so a message that is not UTF-8 is always sent with DATA.
+    let mut message: Vec<u8> = Vec::new();
+    sendable_email.message().read_to_end(&mut message)?;
+    let chunking = config.prefer_chunking && supports_keyword(&ehlo_response, "CHUNKING");
+    let chunked_message = if chunking {
+        String::from_utf8(message).map_err(|e| e.into_bytes())
+    } else {
+        Err(message)
+    };
+    // A dry run stops short of the message
+    let send_data = chunked_message.is_err() && !config.dry_run;
+
+    let mut commands: Vec<String> = vec![
+        MailCommand::new(envelope.from().cloned(), mail_parameters).to_string()
+    ];
+    for to_address in rcpt_indices.iter().map(|i| &envelope.to()[*i]) {
+        let rcpt_parameters = match dsn {
+            Some(dsn) => dsn_rcpt_parameters(dsn, to_address.as_ref()),
+            None => vec![],
+        };
+        commands.push(RcptCommand::new(to_address.clone(), rcpt_parameters).to_string());
+    }
+    if send_data {
+        commands.push(DataCommand.to_string());
+    }
+
+    // With PIPELINING (RFC 2920) the commands through to DATA are sent in one
+    // batch, and their replies are read back afterwards, in order
+    let pipelining = supports_keyword(&ehlo_response, "PIPELINING");
+    let mut batch = if pipelining { Some(commands.concat()) } else { None };
+    let mut commands = commands.into_iter();
+    let mut next_reply = |client: &mut InnerClient<S>| {
+        let command = commands.next().unwrap_or_default();
+        match batch.take() {
+            Some(batch) => client.command(batch),
+            None if pipelining => read_reply(client),
+            None => client.command(command),
+        }
+    };
+
+    next_reply(client)?;
+
+    // A rejected recipient does not stop delivery to the others.  A 4xx defers
+    // just that recipient (so only they are retried), and a 5xx fails just them.
+    // But a 421 means the server is closing the connection, so we give up on the
+    // whole session and everyone left is deferred to the next MX server.
+    let mut accepted: Vec<usize> = Vec::new();
+    for i in rcpt_indices {
+        match next_reply(client) {
+            Ok(response) if config.dry_run => results[i] = Some(dry_run_result(response)),
+            Ok(_) => accepted.push(i),
+            Err(e) if is_service_unavailable(&e) => {
+                info!("(worker) server is unavailable, deferring the session: {:?}", e);
+                return Err(e);
+            }
+            Err(e @ LettreSmtpError::Transient(_)) | Err(e @ LettreSmtpError::Permanent(_)) => {
+                results[i] = Some(classify_result(Err(e)));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if config.dry_run {
+        // Abandon the transaction (QUIT follows when the connection is closed)
+        if let Err(e) = client.command(RsetCommand) {
+            debug!("(worker) RSET failed after a dry run: {:?}", e);
+        }
+        return Ok(());
+    }
+    if accepted.is_empty() {
+        if pipelining && send_data && next_reply(client).is_ok() {
+            // DATA went out in the batch anyway.  The server should have refused
+            // it with no recipients, but since it did not, end it without the message.
+            let _ = client.message(Box::new(::std::io::empty()));
+        }
+        return Ok(());
+    }
+
+    // The reply to the message is for every accepted recipient.  If it times out
+    // once the whole message has been sent, the server may have accepted it.
+    let sent = Rc::new(Cell::new(false));
+    let response = match chunked_message {
+        Ok(message) => send_chunked(client, &message, config.chunk_size, &sent),
+        Err(message) => {
+            next_reply(client)?;
+            client.message(Box::new(SentTracker {
+                message: Cursor::new(message),
+                sent: sent.clone(),
+            }))
+        }
+    };
+    let result = match response {
+        Err(LettreSmtpError::Io(ref ioe)) if sent.get() && is_timeout(ioe) =>
+            post_data_timeout(ioe, config),
+        response => classify_result(response),
+    };
+    for i in accepted {
+        results[i] = Some(result.clone());
+    }
+
+    Ok(())
+}
+
+// The message for client.message(), noting when it has all been read.  lettre
+// writes each piece to the server before reading the next, so by then it has all
+// been sent but for the final "."
+struct SentTracker {
+    message: Cursor<Vec<u8>>,
+    sent: Rc<Cell<bool>>,
+}
+
+impl Read for SentTracker {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let count = self.message.read(buf)?;
+        if count == 0 && !buf.is_empty() {
+            self.sent.set(true);
+        }
+        Ok(count)
+    }
+}
+
+// Whether an I/O error is a timeout.  Unix reports socket timeouts as WouldBlock.
+fn is_timeout(ioe: &::std::io::Error) -> bool {
+    matches!(ioe.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+// The result for recipients whose server timed out after being sent the whole
+// message (see Config::assume_delivered_on_post_data_timeout)
+fn post_data_timeout(ioe: &::std::io::Error, config: &Config) -> DeliveryResult {
+    if config.assume_delivered_on_post_data_timeout {
+        info!("(worker) Timed out after sending the message, assuming delivery: {:?}", ioe);
+        // The server is filled in by the caller (see note_mx_host)
+        DeliveryResult::Delivered(DeliveredResponse {
+            code: 0,
+            message: format!("Assumed delivered: timed out waiting for a reply to the \
+                              message: {:?}", ioe),
+            mx_host: String::new(),
+            encrypted: false,
+            used_helo: false,
+            dry_run: false,
+        })
+    } else {
+        info!("(worker) Delivery Deferred (timed out after sending the message): {:?}", ioe);
+        DeliveryResult::Deferred(1, format!("Timed out waiting for a reply to the message, \
+                                             which may have been delivered: {:?}", ioe))
+    }
+}
+
+// The result for a recipient the server accepted in a dry run
+fn dry_run_result(rcpt_response: Response) -> DeliveryResult {
+    match classify_result(Ok(rcpt_response)) {
+        DeliveryResult::Delivered(response) =>
+            DeliveryResult::Delivered(DeliveredResponse { dry_run: true, ..response }),
+        other => other,
+    }
+}
+
+// HELO, for servers that do not understand EHLO (RFC 5321 section 4.1.1.1).  The
+// reply advertises no extensions.
+struct HeloCommand(ClientId);
+
+impl Display for HeloCommand {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "HELO {}\r\n", self.0)
+    }
+}
+
+// A BDAT command followed by its chunk of the message (RFC 3030)
+struct BdatCommand<'a> {
+    chunk: &'a str,
+    last: bool,
+}
+
+impl<'a> Display for BdatCommand<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "BDAT {}{}\r\n{}", self.chunk.len(), if self.last { " LAST" } else { "" },
+               self.chunk)
+    }
+}
+
+// Send the message in BDAT chunks of at most `chunk_size` bytes (without splitting
+// a character), returning the reply to the last chunk or the first error.  `sent`
+// is set once every chunk before the last has been accepted.
+fn send_chunked<S>(
+    client: &mut InnerClient<S>,
+    message: &str,
+    chunk_size: usize,
+    sent: &Cell<bool>,
+) -> Result<Response, LettreSmtpError>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    let mut rest = message;
+    loop {
+        let mut end = chunk_size.max(1).min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 && !rest.is_empty() {
+            // The chunk size is smaller than this character
+            end = rest.char_indices().nth(1).map(|(i, _)| i).unwrap_or_else(|| rest.len());
+        }
+        let (chunk, remainder) = rest.split_at(end);
+        let last = remainder.is_empty();
+        sent.set(last);
+        let response = client.command(BdatCommand { chunk, last })?;
+        if last {
+            return Ok(response);
+        }
+        rest = remainder;
+    }
+}
 
-    #[allow(unreachable_patterns)] // lettre may add more
-    let result = match mailer.send(sendable_email) {
+// Whether an error is a 421 reply: the server is shutting down or overloaded, and
+// is closing the connection (RFC 5321 section 3.8)
+fn is_service_unavailable(error: &LettreSmtpError) -> bool {
+    match *error {
+        LettreSmtpError::Transient(ref response) =>
+            response.code.category == Category::Connections
+                && response.code.detail == Detail::One,
+        _ => false,
+    }
+}
+
+// Whether an EHLO response advertises an extension keyword
+fn supports_keyword(ehlo_response: &Response, keyword: &str) -> bool {
+    ehlo_response.message
+        .iter()
+        .skip(1) // the first line is the server name
+        .any(|line| {
+            line.split_whitespace()
+                .next()
+                .map(|word| word.eq_ignore_ascii_case(keyword))
+                .unwrap_or(false)
+        })
+}
+
+// RFC 3461 MAIL FROM parameters
+fn dsn_mail_parameters(dsn: &DsnRequest, message_id: &str) -> Vec<MailParameter> {
+    let mut parameters = vec![];
+    if let Some(ret) = dsn.ret {
+        parameters.push(MailParameter::Other {
+            keyword: "RET".to_owned(),
+            value: Some(match ret {
+                DsnReturn::Full => "FULL".to_owned(),
+                DsnReturn::Headers => "HDRS".to_owned(),
+            }),
+        });
+    }
+    parameters.push(MailParameter::Other {
+        keyword: "ENVID".to_owned(),
+        value: Some(dsn.envid.clone().unwrap_or_else(|| message_id.to_owned())),
+    });
+    parameters
+}
+
+// RFC 3461 RCPT TO parameters
+fn dsn_rcpt_parameters(dsn: &DsnRequest, recipient: &str) -> Vec<RcptParameter> {
+    let mut parameters = vec![];
+    if !dsn.notify.is_empty() {
+        let notify = if dsn.notify.contains(&DsnNotify::Never) {
+            "NEVER".to_owned()
+        } else {
+            dsn.notify
+                .iter()
+                .map(|n| match *n {
+                    DsnNotify::Success => "SUCCESS",
+                    DsnNotify::Failure => "FAILURE",
+                    DsnNotify::Delay => "DELAY",
+                    DsnNotify::Never => unreachable!(),
+                })
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
+        parameters.push(RcptParameter::Other {
+            keyword: "NOTIFY".to_owned(),
+            value: Some(notify),
+        });
+    }
+    parameters.push(RcptParameter::Other {
+        keyword: "ORCPT".to_owned(),
+        value: Some(format!("rfc822;{}", recipient)),
+    });
+    parameters
+}
+
+// Read a reply without sending a command, such as the server greeting.  lettre's
+// read_response() is private, but command() writes its argument and then reads a
+// response, so an empty command reads just the next reply.
+pub fn read_reply<S>(client: &mut InnerClient<S>) -> Result<Response, LettreSmtpError>
+    where S: Connector + Read + Write + Timeout + Debug
+{
+    client.command("")
+}
+
+// Map the outcome of an SMTP session to a DeliveryResult
+#[allow(unreachable_patterns)] // lettre may add more
+pub fn classify_result(result: Result<Response, LettreSmtpError>) -> DeliveryResult {
+    const IGNORED_ATTEMPTS: u8 = 1;
+
+    match result {
         Ok(response) => {
             match response.code.severity {
                 Severity::PositiveCompletion | Severity::PositiveIntermediate => {
                     info!("(worker) Delivery Success: {:?}", response);
-                    DeliveryResult::Delivered(format!("{:?}", response))
+                    // The server is filled in by the caller (see note_mx_host)
+                    DeliveryResult::Delivered(DeliveredResponse {
+                        code: format!("{}", response.code).parse().unwrap_or(0),
+                        message: response.message.join(" "),
+                        mx_host: String::new(),
+                        encrypted: false,
+                        used_helo: false,
+                        dry_run: false,
+                    })
                 }
                 Severity::TransientNegativeCompletion => {
                     info!("(worker) Delivery Deferred: {:?}", response);
@@ -167,6 +884,10 @@ pub fn smtp_delivery(
             info!("(worker) Delivery Failed (utf8 parsing error): {:?}", fue);
             DeliveryResult::Failed(format!("utf8 parsing error: {:?}", fue))
         },
+        Err(LettreSmtpError::Client(s)) if TRANSIENT_CLIENT_ERRORS.contains(&s) => {
+            info!("(worker) Delivery Deferred (internal client error): {}", s);
+            DeliveryResult::Deferred(IGNORED_ATTEMPTS, format!("internal client error: {:?}", s))
+        },
         Err(LettreSmtpError::Client(s)) => {
             info!("(worker) Delivery Failed (internal client error): {}", s);
             DeliveryResult::Failed(format!("internal client error: {:?}", s))
@@ -184,6 +905,7 @@ pub fn smtp_delivery(
                 ErrorKind::AddrInUse |
                 ErrorKind::BrokenPipe |
                 ErrorKind::TimedOut |
+                ErrorKind::WouldBlock |
                 ErrorKind::Interrupted => {
                     info!("(worker) Delivery Deferred (I/O error): {:?}", ioe);
                     DeliveryResult::Deferred(IGNORED_ATTEMPTS, format!("I/O error: {:?}", ioe))
@@ -211,6 +933,13 @@ pub fn smtp_delivery(
             info!("(worker) Delivery Failed (TLS error): {:?}", tlse);
             DeliveryResult::Failed(format!("TLS error: {:?}", tlse))
         },
+        // A reply cut short by the server closing the connection (nom's ErrorKind
+        // is not re-exported by lettre, so we go by its debug representation)
+        Err(LettreSmtpError::Parsing(nomek)) if format!("{:?}", nomek) == "Complete" => {
+            info!("(worker) Delivery Deferred (connection closed unexpectedly)");
+            DeliveryResult::Deferred(IGNORED_ATTEMPTS,
+                                     "Connection closed unexpectedly".to_owned())
+        },
         Err(LettreSmtpError::Parsing(nomek)) => {
             info!("(worker) Delivery Failed (Parsing error): {:?}", nomek);
             DeliveryResult::Failed(format!("Parsing error: {:?}", nomek))
@@ -219,9 +948,5 @@ pub fn smtp_delivery(
             info!("(worker) delivery failed response: {:?}", e);
             DeliveryResult::Failed(format!("{:?}", e))
         }
-    };
-
-    mailer.close();
-
-    result
+    }
 }
@@ -1,7 +1,8 @@
 use std::cmp::{Ord, Ordering, PartialOrd};
+use std::collections::BTreeSet;
 use std::time::Instant;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum TaskType {
     Resend,
 }
@@ -11,18 +12,43 @@ pub struct Task {
     pub tasktype: TaskType,
     pub time: Instant,
     pub message_id: String,
+    // SendOptions::priority of the email
+    pub priority: u8,
 }
 
+// Tasks are done in time order, and those due at the same time in priority order
+// (highest first).  The rest of the fields break any tie, since a BTreeSet takes
+// tasks that compare equal to be the same task and would keep only one of them.
 impl Ord for Task {
     fn cmp(&self, other: &Self) -> Ordering {
         self.time.cmp(&other.time)
+            .then_with(|| other.priority.cmp(&self.priority))
+            .then_with(|| self.message_id.cmp(&other.message_id))
+            .then_with(|| self.tasktype.cmp(&other.tasktype))
     }
 }
 
 impl PartialOrd for Task {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.time.partial_cmp(&other.time)
+        Some(self.cmp(other))
     }
 }
 
 impl Eq for Task {}
+
+// Add a task, unless the email already has one of the same type due no later.  A
+// task due later than this one is replaced by it, so an email never has two.
+pub fn add_task(tasks: &mut BTreeSet<Task>, task: Task) {
+    let existing = tasks
+        .iter()
+        .find(|t| t.tasktype == task.tasktype && t.message_id == task.message_id)
+        .cloned();
+    if let Some(existing) = existing {
+        if existing.time <= task.time {
+            debug!("(worker) email {} already has a task due", task.message_id);
+            return;
+        }
+        tasks.remove(&existing);
+    }
+    tasks.insert(task);
+}
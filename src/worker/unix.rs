@@ -0,0 +1,60 @@
+use lettre::smtp::client::net::{ClientTlsParameters, Connector, Timeout};
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+// A Unix domain socket connection that lettre's client can run a session over.
+// There is no TLS over these (they are local), so STARTTLS is never attempted.
+#[derive(Debug)]
+pub struct UnixSocketStream(UnixStream);
+
+impl UnixSocketStream {
+    pub fn connect(path: &Path) -> Result<UnixSocketStream, IoError> {
+        Ok(UnixSocketStream(UnixStream::connect(path)?))
+    }
+}
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+
+impl Connector for UnixSocketStream {
+    fn connect(
+        _addr: &SocketAddr,
+        _tls_parameters: Option<&ClientTlsParameters>,
+    ) -> Result<UnixSocketStream, IoError> {
+        Err(IoError::new(ErrorKind::Unsupported, "Unix sockets have no socket address"))
+    }
+
+    fn upgrade_tls(&mut self, _tls_parameters: &ClientTlsParameters) -> Result<(), IoError> {
+        Err(IoError::new(ErrorKind::Unsupported, "TLS is not supported over Unix sockets"))
+    }
+
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+}
+
+impl Timeout for UnixSocketStream {
+    fn set_read_timeout(&mut self, duration: Option<Duration>) -> Result<(), IoError> {
+        self.0.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&mut self, duration: Option<Duration>) -> Result<(), IoError> {
+        self.0.set_write_timeout(duration)
+    }
+}